@@ -0,0 +1,11 @@
+#version 450
+
+layout(location=0) in vec2 xy_pos;
+layout(location=1) in vec4 color;
+
+layout(location=0) out vec4 color_out;
+
+void main() {
+    gl_Position = vec4(xy_pos, 0.0, 1.0);
+    color_out = color;
+}
@@ -1,6 +1,7 @@
 #version 450
 
 layout(location=0) in vec2 uv_pos;
+layout(location=1) in vec4 color;
 
 layout(location=0) out vec4 frag_color;
 
@@ -8,5 +9,6 @@ layout(set=0, binding=0) uniform texture2D logo_texture;
 layout(set=0, binding=1) uniform sampler logo_sampler;
 
 void main() {
-    frag_color = texture(sampler2D(logo_texture, logo_sampler), uv_pos);
+    // The atlas holds white glyphs, with the coverage in the alpha channel
+    frag_color = color * texture(sampler2D(logo_texture, logo_sampler), uv_pos);
 }
@@ -0,0 +1,120 @@
+// The commands rakoune has without any configuration. Most of them only check
+// their arguments and call a method of the state.
+
+use std::path::Path;
+
+use super::Commands;
+use crate::keymap::{self, Binding, Mapping};
+use crate::options::{Scope, Type};
+
+const MAP_USAGE: &str = "map [-docstring <text>] [-command] <mode> <keys> <keys or command>";
+
+// Options are set globally unless the first argument is a scope, and there are enough arguments after it
+fn split_scope(args: &[String], min_rest: usize) -> (Scope, &[String]) {
+    match Scope::parse(&args[0]) {
+        Some(scope) if args.len() > min_rest => (scope, &args[1..]),
+        _ => (Scope::Global, args),
+    }
+}
+
+pub(super) fn register(commands: &mut Commands) {
+    commands.register("edit", &["e"], "edit <path>", 1..=1, |state, args| state.edit(Path::new(&args[0]), false));
+    commands.register("edit!", &["e!"], "edit! <path>", 1..=1, |state, args| state.edit(Path::new(&args[0]), true));
+
+    commands.register("write", &["w"], "write [<path>]", 0..=1, |state, args| match args {
+        [path] => state.write_copy(Path::new(path)),
+        _ => state.write(),
+    });
+    commands.register("write-as", &[], "write-as <path>", 1..=1, |state, args| state.write_as(Path::new(&args[0])));
+
+    commands.register("quit", &["q"], "quit", 0..=0, |state, _| state.quit(false));
+    commands.register("quit!", &["q!"], "quit!", 0..=0, |state, _| state.quit(true));
+    commands.register("write-quit", &["wq"], "write-quit", 0..=0, |state, _| {
+        state.write()?;
+        state.quit(false)
+    });
+
+    commands.register("source", &[], "source <path>", 1..=1, |state, args| state.source(Path::new(&args[0])));
+
+    commands.register("echo", &[], "echo [<text>...]", 0..=usize::MAX, |_, args| Ok(args.join(" ")));
+
+    commands.register("set-option", &["set"], "set-option [<scope>] <name> <value>...", 2..=usize::MAX, |state, args| {
+        let (scope, args) = split_scope(args, 2);
+        state.set_option(scope, &args[0], &args[1..])
+    });
+    commands.register("unset-option", &["unset"], "unset-option [<scope>] <name>", 1..=2, |state, args| {
+        let (scope, args) = split_scope(args, 1);
+        state.unset_option(scope, &args[0])
+    });
+    commands.register("declare-option", &[], "declare-option <type> <name> [<value>...]", 2..=usize::MAX, |state, args| {
+        let ty = Type::parse(&args[0])?;
+        let default = ty.parse_value(&args[1], &args[2..])?;
+        state.options.declare(&args[1], ty, default)?;
+        Ok(String::new())
+    });
+
+    commands.register("theme", &[], "theme <name>", 1..=1, |state, args| state.set_theme(&args[0]));
+
+    commands.register("zoom", &[], "zoom in|out|reset", 1..=1, |state, args| {
+        match args[0].as_str() {
+            "in" => state.font.zoom_by(1),
+            "out" => state.font.zoom_by(-1),
+            "reset" => state.font.zoom = 0,
+            _ => return Err("zoom takes in, out or reset".to_string()),
+        }
+        Ok(String::new())
+    });
+
+    commands.register("map", &[], MAP_USAGE, 3..=usize::MAX, |state, mut args| {
+        let mut docstring = None;
+        let mut is_command = false;
+        loop {
+            match args {
+                [flag, text, rest @ ..] if flag == "-docstring" => {
+                    docstring = Some(text.clone());
+                    args = rest;
+                }
+                [flag, rest @ ..] if flag == "-command" => {
+                    is_command = true;
+                    args = rest;
+                }
+                _ => break,
+            }
+        }
+
+        let (mode, keys, target) = match args {
+            [mode, keys, target] => (keymap::parse_mode(mode)?, keymap::parse_keys(keys)?, target),
+            _ => return Err(format!("Usage: {}", MAP_USAGE)),
+        };
+        if keys.is_empty() {
+            return Err("Can't map no keys".to_string());
+        }
+        let mapping = if is_command {
+            Mapping::Command(target.clone())
+        } else {
+            Mapping::Keys(keymap::parse_keys(target)?)
+        };
+        state.keymaps.get_mut(mode).map(keys, Binding { mapping, docstring });
+        Ok(String::new())
+    });
+
+    commands.register("unmap", &[], "unmap <mode> <keys>", 2..=2, |state, args| {
+        let mode = keymap::parse_mode(&args[0])?;
+        let keys = keymap::parse_keys(&args[1])?;
+        if state.keymaps.get_mut(mode).unmap(&keys) {
+            Ok(String::new())
+        } else {
+            Err(format!("{} isn't mapped in {} mode", args[1], args[0]))
+        }
+    });
+
+    commands.register("info", &[], "info <text>", 1..=1, |state, args| {
+        state.info = Some(args[0].clone());
+        Ok(String::new())
+    });
+
+    commands.register("debug-dump", &[], "debug-dump", 0..=0, |state, _| {
+        state.debug_dump_requested = true;
+        Ok(String::new())
+    });
+}
@@ -0,0 +1,262 @@
+// The command language of the prompt. A command line is split into commands,
+// and each command into words, the first of which names the command:
+//
+//     write-as 'notes on toki pona.txt'; set-option font "Fira Code" %{linja pona}
+//
+// Words are separated by whitespace, and commands by ; or newlines. Quotes and
+// %{...} blocks make one word of everything inside them. Blocks can also be
+// written with (), [] or <>, and the brackets nest inside them. Only double
+// quotes have escapes, \" and \\. Outside of quotes a backslash keeps the char
+// after it as it is, and # starts a comment which lasts to the end of the line.
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::str::Chars;
+
+use crate::state::State;
+
+mod builtins;
+
+/// A command as it was written, split into words
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invocation {
+    pub words: Vec<String>,
+    /// The line of the command line the command starts on, from 0
+    pub line: usize,
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        if ch == '\n' {
+            self.line += 1;
+        }
+        Some(ch)
+    }
+
+    // Reads up to the closing quote, after the opening one. Errors are given with the line they're on.
+    fn quoted(&mut self, quote: char, word: &mut String) -> Result<(), (usize, String)> {
+        let start_line = self.line;
+        loop {
+            match self.next() {
+                Some(ch) if ch == quote => return Ok(()),
+                Some('\\') if quote == '"' && matches!(self.chars.peek(), Some('"') | Some('\\')) => {
+                    word.extend(self.next());
+                }
+                Some(ch) => word.push(ch),
+                None => return Err((start_line, format!("unterminated {}", quote))),
+            }
+        }
+    }
+
+    // Reads up to the closing bracket, after the opening one
+    fn block(&mut self, open: char, word: &mut String) -> Result<(), (usize, String)> {
+        let close = match open {
+            '{' => '}',
+            '(' => ')',
+            '[' => ']',
+            _ => '>',
+        };
+        let start_line = self.line;
+        let mut depth = 0;
+        loop {
+            match self.next() {
+                Some(ch) if ch == close && depth == 0 => return Ok(()),
+                Some(ch) => {
+                    if ch == open {
+                        depth += 1;
+                    } else if ch == close {
+                        depth -= 1;
+                    }
+                    word.push(ch);
+                }
+                None => return Err((start_line, format!("unterminated %{}", open))),
+            }
+        }
+    }
+}
+
+/// Splits a command line into commands. Empty commands are left out.
+pub fn parse(text: &str) -> Result<Vec<Invocation>, String> {
+    match parse_until_error(text) {
+        (invocations, None) => Ok(invocations),
+        (_, Some((line, e))) => Err(format!("line {}: {}", line + 1, e)),
+    }
+}
+
+/// Splits a file into commands like parse, but a command which can't be parsed
+/// doesn't stop the ones after it. Its error, with the line it's on, takes its
+/// place, and parsing starts again on the next line.
+pub fn parse_file(text: &str) -> Vec<Result<Invocation, (usize, String)>> {
+    let mut results = Vec::new();
+    let mut rest = text;
+    // The line rest starts on
+    let mut first_line = 0;
+    loop {
+        let (invocations, error) = parse_until_error(rest);
+        results.extend(invocations.into_iter().map(|invocation| Ok(Invocation { line: invocation.line + first_line, ..invocation })));
+        let (line, e) = match error {
+            Some(error) => error,
+            None => return results,
+        };
+        results.push(Err((first_line + line, e)));
+
+        match rest.match_indices('\n').nth(line) {
+            Some((newline, _)) => rest = &rest[newline + 1..],
+            None => return results,
+        }
+        first_line += line + 1;
+    }
+}
+
+// Splits text into commands up to the first one which can't be parsed. Returns
+// the commands before it, and the error with the line it's on.
+fn parse_until_error(text: &str) -> (Vec<Invocation>, Option<(usize, String)>) {
+    let mut parser = Parser { chars: text.chars().peekable(), line: 0 };
+    let mut invocations = Vec::new();
+    let mut words: Vec<String> = Vec::new();
+    // The word being read, None between words
+    let mut word: Option<String> = None;
+    let mut start_line = 0;
+
+    loop {
+        let line = parser.line;
+        match parser.next() {
+            ch @ None | ch @ Some('\n') | ch @ Some(';') => {
+                words.extend(word.take());
+                if !words.is_empty() {
+                    invocations.push(Invocation { words: std::mem::take(&mut words), line: start_line });
+                }
+                if ch.is_none() {
+                    return (invocations, None);
+                }
+            }
+            Some(ch) if ch.is_whitespace() => words.extend(word.take()),
+            Some('#') if word.is_none() => {
+                while parser.chars.peek().map(|&ch| ch != '\n').unwrap_or(false) {
+                    parser.next();
+                }
+            }
+            Some(ch) => {
+                let starts_word = word.is_none();
+                if starts_word && words.is_empty() {
+                    start_line = line;
+                }
+                let word = word.get_or_insert_with(String::new);
+                let result = match ch {
+                    '\'' | '"' => parser.quoted(ch, word),
+                    '%' if starts_word && matches!(parser.chars.peek(), Some('{') | Some('(') | Some('[') | Some('<')) => {
+                        let open = parser.next().unwrap_or('{');
+                        parser.block(open, word)
+                    }
+                    '\\' => {
+                        word.extend(parser.next());
+                        Ok(())
+                    }
+                    _ => {
+                        word.push(ch);
+                        Ok(())
+                    }
+                };
+                if let Err(error) = result {
+                    return (invocations, Some(error));
+                }
+            }
+        }
+    }
+}
+
+type Run = dyn Fn(&mut State, &[String]) -> Result<String, String>;
+
+pub struct Command {
+    /// How the command is used, e.g. "write-as <path>"
+    pub usage: String,
+    params: RangeInclusive<usize>,
+    run: Rc<Run>,
+}
+
+impl Command {
+    /// Runs the command with the words after its name
+    pub fn run(&self, state: &mut State, args: &[String]) -> Result<String, String> {
+        if !self.params.contains(&args.len()) {
+            return Err(format!("Usage: {}", self.usage));
+        }
+        (self.run)(state, args)
+    }
+}
+
+/// The commands which can be run, by name and by alias
+#[derive(Clone, Default)]
+pub struct Commands {
+    commands: HashMap<String, Rc<Command>>,
+}
+
+impl Commands {
+    /// The commands rakoune has without any configuration
+    pub fn builtin() -> Self {
+        let mut commands = Commands::default();
+        builtins::register(&mut commands);
+        commands
+    }
+
+    /// Adds a command, replacing any command with the same name or alias. params is
+    /// how many words it takes after its name, and it isn't run with any other number.
+    pub fn register(
+        &mut self,
+        name: &str,
+        aliases: &[&str],
+        usage: &str,
+        params: RangeInclusive<usize>,
+        run: impl Fn(&mut State, &[String]) -> Result<String, String> + 'static,
+    ) {
+        let command = Rc::new(Command {
+            usage: usage.to_string(),
+            params,
+            run: Rc::new(run),
+        });
+        for name in std::iter::once(&name).chain(aliases) {
+            self.commands.insert(name.to_string(), Rc::clone(&command));
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<Command>> {
+        self.commands.get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+fn words_of(text: &str) -> Vec<Vec<String>> {
+    parse(text).unwrap().into_iter().map(|invocation| invocation.words).collect()
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(words_of("write-as  notes.txt "), vec![vec!["write-as", "notes.txt"]]);
+    assert_eq!(words_of(r#"echo 'a "b"' "c \"d\" \n" e\ f"#), vec![vec!["echo", r#"a "b""#, r#"c "d" \n"#, "e f"]]);
+    assert_eq!(words_of("echo %{a {b} c} %<d> x%{y}"), vec![vec!["echo", "a {b} c", "d", "x%{y}"]]);
+    assert_eq!(words_of("echo ''; q"), vec![vec!["echo", ""], vec!["q"]]);
+
+    let invocations = parse("# A comment\n\necho %{a\nb} # another\nq").unwrap();
+    assert_eq!(invocations, vec![
+        Invocation { words: vec!["echo".to_string(), "a\nb".to_string()], line: 2 },
+        Invocation { words: vec!["q".to_string()], line: 4 },
+    ]);
+
+    assert_eq!(parse("echo\n%{a"), Err("line 2: unterminated %{".to_string()));
+    assert_eq!(parse("echo 'a"), Err("line 1: unterminated '".to_string()));
+
+    let results = parse_file("echo a\necho %{b\nc\n\necho 'd");
+    assert_eq!(results, vec![
+        Ok(Invocation { words: vec!["echo".to_string(), "a".to_string()], line: 0 }),
+        Err((1, "unterminated %{".to_string())),
+        Ok(Invocation { words: vec!["c".to_string()], line: 2 }),
+        Err((4, "unterminated '".to_string())),
+    ]);
+}
@@ -0,0 +1,21 @@
+// Where rakoune looks for its configuration: $XDG_CONFIG_HOME/rakoune, or
+// ~/.config/rakoune. The rakrc file in it is run with the command language at
+// startup, e.g.
+//
+//     theme light
+//     set-option font-size 20
+//     map -docstring 'write and quit' -command normal <space>q write-quit
+
+use std::env;
+use std::path::PathBuf;
+
+pub fn config_dir() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("rakoune"))
+}
+
+pub fn rakrc_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("rakrc"))
+}
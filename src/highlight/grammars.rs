@@ -0,0 +1,85 @@
+// The grammars rakoune knows about without any configuration
+
+use std::rc::Rc;
+
+use super::Grammar;
+
+fn rust() -> Result<Grammar, String> {
+    Grammar::new("rust", r"\.rs$")?
+        .region("comment", "//", "$", None)?
+        .region("comment", r"/\*", r"\*/", None)?
+        .region("string", "\"", "\"", Some(r"\\."))?
+        .region_highlighter(r"\\.", &[(0, "meta")])?
+        .region("string", "\\br#\"", "\"#", None)?
+        .region("string", "\\br\"", "\"", None)?
+        .highlighter(r"'\w+\b", &[(0, "variable")])?
+        .highlighter(r"'(?:\\.|[^\\'])'", &[(0, "string")])?
+        .highlighter(r"\b[A-Z]\w*\b", &[(0, "type")])?
+        .highlighter(r"\b(?:u8|u16|u32|u64|u128|usize|i8|i16|i32|i64|i128|isize|f32|f64|bool|char|str)\b", &[(0, "type")])?
+        .highlighter(r"\b(\w+)\s*(?:::\s*<[^>]*>\s*)?\(", &[(1, "function")])?
+        .highlighter(r"\b(\w+!)\s*[(\[{]", &[(1, "meta")])?
+        .highlighter(r"#!?\[[^\]]*\]", &[(0, "meta")])?
+        .keywords("keyword", &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+            "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+            "return", "self", "Self", "static", "struct", "super", "trait", "type", "unsafe", "use",
+            "where", "while",
+        ])?
+        .keywords("value", &["true", "false", "None", "Some", "Ok", "Err"])?
+        .highlighter(r"\b\d[\d_]*(?:\.[\d_]+)?(?:[eE][+-]?\d+)?(?:[iuf](?:8|16|32|64|128|size))?\b", &[(0, "value")])?
+        .highlighter(r"\b0[xob][\da-fA-F_]+\b", &[(0, "value")])
+}
+
+fn toml() -> Result<Grammar, String> {
+    Grammar::new("toml", r"\.toml$|^Cargo\.lock$")?
+        .region("comment", "#", "$", None)?
+        .region("string", "\"\"\"", "\"\"\"", Some(r"\\."))?
+        .region("string", "'''", "'''", None)?
+        .region("string", "\"", "\"", Some(r"\\."))?
+        .region_highlighter(r"\\.", &[(0, "meta")])?
+        .region("string", "'", "'", None)?
+        .highlighter(r"^\s*(\[\[?[^\]]*\]\]?)", &[(1, "meta")])?
+        .highlighter(r"^\s*([\w.-]+)\s*=", &[(1, "variable")])?
+        .highlighter(r"(?:^|[,{]\s*)([\w-]+)\s*=", &[(1, "variable")])?
+        .keywords("value", &["true", "false", "inf", "nan"])?
+        .highlighter(r"[+-]?\b\d[\d_:.T-]*(?:[eE][+-]?\d+)?Z?\b", &[(0, "value")])
+}
+
+fn shell() -> Result<Grammar, String> {
+    Grammar::new("shell", r"\.(?:sh|bash|zsh)$|^\.(?:bashrc|zshrc|profile)$")?
+        .region("comment", r"(?:^|\s)(#)", "$", None)?
+        .region("string", "\"", "\"", Some(r"\\."))?
+        .region_highlighter(r"\$(?:\{[^}]*\}|[\w@#?*!$-]+)", &[(0, "variable")])?
+        .region("string", "'", "'", None)?
+        .highlighter(r"^\s*(\w+)\s*\(\)", &[(1, "function")])?
+        .highlighter(r"\$(?:\{[^}]*\}|[\w@#?*!$-]+)", &[(0, "variable")])?
+        .highlighter(r"^\s*(\w+)=", &[(1, "variable")])?
+        .keywords("keyword", &[
+            "if", "then", "else", "elif", "fi", "for", "while", "until", "do", "done", "case", "esac",
+            "in", "function", "return", "local", "export", "readonly", "break", "continue",
+        ])?
+        .highlighter(r"\s(-{1,2}[\w-]+)", &[(1, "value")])
+}
+
+/// Grammars for Rust, TOML and shell scripts
+pub fn builtin_grammars() -> Vec<Rc<Grammar>> {
+    vec![rust(), toml(), shell()]
+        .into_iter()
+        .map(|grammar| Rc::new(grammar.expect("Builtin grammars are valid")))
+        .collect()
+}
+
+#[test]
+fn test_builtin_grammars() {
+    use std::path::Path;
+
+    let grammars = builtin_grammars();
+    assert_eq!(grammars.len(), 3);
+    assert_eq!(super::grammar_for_path(&grammars, Path::new("src/main.rs")).unwrap().name, "rust");
+    assert_eq!(super::grammar_for_path(&grammars, Path::new("Cargo.toml")).unwrap().name, "toml");
+    assert!(super::grammar_for_path(&grammars, Path::new("notes.txt")).is_none());
+
+    // The space before a comment isn't part of it
+    let shell = super::grammar_for_path(&grammars, Path::new("build.sh")).unwrap();
+    assert!(super::faces_of_line(&shell, "echo a# b # c", None).contains(&("# c", "comment".to_string())));
+}
@@ -0,0 +1,322 @@
+// Kakoune style syntax highlighting. A grammar splits the buffer into regions,
+// such as strings and comments, which start and end at matches of regexes and
+// can span several lines. The text inside and outside of the regions is then
+// highlighted with more regexes.
+//
+// Lines are highlighted from the top, remembering which region each line ends
+// in, so after an edit only the lines from the edited one down to the bottom of
+// the viewport need to be highlighted again.
+
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+
+use regex::Regex;
+
+use crate::rope::Rope;
+use crate::style::{Faces, FacedRanges};
+
+mod grammars;
+pub use grammars::builtin_grammars;
+
+// Byte ranges of a line and the names of their faces
+type LineHighlights = Vec<(Range<usize>, Rc<str>)>;
+
+fn compile(regex: &str) -> Result<Regex, String> {
+    Regex::new(regex).map_err(|e| format!("Invalid regex {}: {}", regex, e))
+}
+
+/// Gives faces to capture groups of all matches of a regex. Group 0 is the whole match.
+pub struct RegexHighlighter {
+    regex: Regex,
+    captures: Vec<(usize, Rc<str>)>,
+}
+
+impl RegexHighlighter {
+    pub fn new(regex: &str, captures: &[(usize, &str)]) -> Result<Self, String> {
+        Ok(Self {
+            regex: compile(regex)?,
+            captures: captures.iter().map(|&(group, face)| (group, Rc::from(face))).collect(),
+        })
+    }
+
+    // Matches inside the range, with the rest of the line before it as context for ^ and \b
+    fn highlight(&self, line: &str, range: Range<usize>, out: &mut LineHighlights) {
+        let text = &line[..range.end];
+        let mut pos = range.start;
+        while let Some(captures) = self.regex.captures_at(text, pos) {
+            for (group, face) in &self.captures {
+                if let Some(m) = captures.get(*group) {
+                    out.push((m.start()..m.end(), face.clone()));
+                }
+            }
+
+            let whole = captures.get(0).map(|m| m.start()..m.end()).unwrap_or(pos..pos);
+            pos = if whole.end > whole.start {
+                whole.end
+            } else {
+                // Empty matches would be found again at the same place
+                match text[whole.end..].chars().next() {
+                    Some(ch) => whole.end + ch.len_utf8(),
+                    None => break,
+                }
+            };
+        }
+    }
+}
+
+struct Region {
+    start: Regex,
+    end: Regex,
+    // Matches of this are skipped when looking for the end, e.g. escaped quotes in strings
+    skip: Option<Regex>,
+    face: Rc<str>,
+    highlighters: Vec<RegexHighlighter>,
+}
+
+impl Region {
+    // Where the region starts, looking from pos. If start has a group, only the group is part
+    // of the region, so start can match text before it, like the space before a shell comment.
+    fn find_start(&self, line: &str, pos: usize) -> Option<Range<usize>> {
+        let captures = self.start.captures_at(line, pos)?;
+        let m = captures.get(1).or_else(|| captures.get(0))?;
+        Some(m.start()..m.end())
+    }
+
+    // Where the region ends, looking from pos
+    fn find_end(&self, line: &str, mut pos: usize) -> Option<usize> {
+        loop {
+            let end = self.end.find_at(line, pos)?;
+            match self.skip.as_ref().and_then(|skip| skip.find_at(line, pos)) {
+                Some(skipped) if skipped.start() < end.start() && skipped.end() > pos => pos = skipped.end(),
+                _ => return Some(end.end()),
+            }
+        }
+    }
+
+    fn highlight(&self, line: &str, range: Range<usize>, out: &mut LineHighlights) {
+        out.push((range.clone(), self.face.clone()));
+        for highlighter in &self.highlighters {
+            highlighter.highlight(line, range.clone(), out);
+        }
+    }
+}
+
+pub struct Grammar {
+    pub name: String,
+    // Matched against file names
+    file_pattern: Regex,
+    regions: Vec<Region>,
+    // For the text outside of regions
+    highlighters: Vec<RegexHighlighter>,
+}
+
+impl Grammar {
+    pub fn new(name: &str, file_pattern: &str) -> Result<Self, String> {
+        Ok(Self {
+            name: name.to_string(),
+            file_pattern: compile(file_pattern)?,
+            regions: Vec::new(),
+            highlighters: Vec::new(),
+        })
+    }
+
+    /// Adds a region from a match of start to a match of end, skipping matches of skip.
+    /// When several regions start at the same place, the one added first is used.
+    /// If start has a group, the region starts at the group instead of the whole match.
+    pub fn region(mut self, face: &str, start: &str, end: &str, skip: Option<&str>) -> Result<Self, String> {
+        self.regions.push(Region {
+            start: compile(start)?,
+            end: compile(end)?,
+            skip: skip.map(compile).transpose()?,
+            face: Rc::from(face),
+            highlighters: Vec::new(),
+        });
+        Ok(self)
+    }
+
+    /// Adds a highlighter for the text inside the last added region
+    pub fn region_highlighter(mut self, regex: &str, captures: &[(usize, &str)]) -> Result<Self, String> {
+        let highlighter = RegexHighlighter::new(regex, captures)?;
+        match self.regions.last_mut() {
+            Some(region) => region.highlighters.push(highlighter),
+            None => return Err(format!("{} has no region to highlight", self.name)),
+        }
+        Ok(self)
+    }
+
+    /// Adds a highlighter for the text outside of regions. Later highlighters are drawn on top.
+    pub fn highlighter(mut self, regex: &str, captures: &[(usize, &str)]) -> Result<Self, String> {
+        self.highlighters.push(RegexHighlighter::new(regex, captures)?);
+        Ok(self)
+    }
+
+    /// Highlights whole words outside of regions
+    pub fn keywords(self, face: &str, words: &[&str]) -> Result<Self, String> {
+        let regex = format!(r"\b(?:{})\b", words.join("|"));
+        self.highlighter(&regex, &[(0, face)])
+    }
+
+    pub fn matches_path(&self, path: &Path) -> bool {
+        path.file_name()
+            .map(|name| self.file_pattern.is_match(&name.to_string_lossy()))
+            .unwrap_or(false)
+    }
+
+    // Highlights a line which starts inside start_region. Returns the highlights
+    // and the region the line ends inside of.
+    fn highlight_line(&self, line: &str, start_region: Option<usize>) -> (LineHighlights, Option<usize>) {
+        let mut highlights = Vec::new();
+        let mut pos = 0;
+        // The region pos is inside of, and where it started on this line
+        let mut region = start_region.map(|region_idx| (region_idx, 0));
+
+        loop {
+            match region {
+                Some((region_idx, region_start)) => {
+                    let current = &self.regions[region_idx];
+                    let end = match current.find_end(line, pos) {
+                        Some(end) => end,
+                        None => {
+                            current.highlight(line, region_start..line.len(), &mut highlights);
+                            return (highlights, Some(region_idx));
+                        }
+                    };
+                    current.highlight(line, region_start..end, &mut highlights);
+                    region = None;
+                    pos = end;
+
+                    // An empty region would start again at the same place forever
+                    if end == region_start {
+                        match line[pos..].chars().next() {
+                            Some(ch) => pos += ch.len_utf8(),
+                            None => return (highlights, None),
+                        }
+                    }
+                }
+                None => {
+                    let next_region = self.regions
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(region_idx, region)| region.find_start(line, pos).map(|start| (region_idx, start)))
+                        .min_by_key(|(region_idx, start)| (start.start, *region_idx));
+
+                    let outside_end = next_region.as_ref().map(|(_, start)| start.start).unwrap_or(line.len());
+                    for highlighter in &self.highlighters {
+                        highlighter.highlight(line, pos..outside_end, &mut highlights);
+                    }
+
+                    match next_region {
+                        Some((region_idx, start)) => {
+                            region = Some((region_idx, start.start));
+                            pos = start.end;
+                        }
+                        None => return (highlights, None),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The grammar for a file, if any of the grammars match its name
+pub fn grammar_for_path(grammars: &[Rc<Grammar>], path: &Path) -> Option<Rc<Grammar>> {
+    grammars.iter().find(|grammar| grammar.matches_path(path)).cloned()
+}
+
+struct HighlightedLine {
+    highlights: LineHighlights,
+    end_region: Option<usize>,
+}
+
+/// Highlights a buffer with a grammar, remembering highlighted lines until they are edited
+pub struct Highlighter {
+    grammar: Option<Rc<Grammar>>,
+    // Every line from the top which has been highlighted
+    lines: Vec<HighlightedLine>,
+}
+
+impl Highlighter {
+    pub fn new(grammar: Option<Rc<Grammar>>) -> Self {
+        Self {
+            grammar,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Forgets the highlights of the line and all lines below it
+    pub fn invalidate_from(&mut self, line_idx: usize) {
+        self.lines.truncate(line_idx);
+    }
+
+    /// The faces of the lines, highlighting them and the lines above them first if needed
+    pub fn highlights(&mut self, content: &Rope, lines: Range<usize>, faces: &Faces) -> FacedRanges {
+        let mut ranges = FacedRanges::new();
+        let grammar = match &self.grammar {
+            Some(grammar) => grammar,
+            None => return ranges,
+        };
+
+        let n_unhighlighted = lines.end.saturating_sub(self.lines.len());
+        for line in content.lines_from(self.lines.len()).take(n_unhighlighted) {
+            let start_region = self.lines.last().and_then(|line| line.end_region);
+            let (highlights, end_region) = grammar.highlight_line(&line, start_region);
+            self.lines.push(HighlightedLine { highlights, end_region });
+        }
+
+        for line_idx in lines {
+            let line_start = content.line_to_byte(line_idx);
+            for (range, face) in &self.lines[line_idx].highlights {
+                ranges.push(line_start + range.start..line_start + range.end, faces.get(face));
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+fn faces_of_line<'a>(grammar: &Grammar, line: &'a str, start_region: Option<usize>) -> Vec<(&'a str, String)> {
+    let (highlights, _) = grammar.highlight_line(line, start_region);
+    highlights.iter().map(|(range, face)| (&line[range.clone()], face.to_string())).collect()
+}
+
+#[test]
+fn test_regions() {
+    let grammar = Grammar::new("test", r"\.test$").unwrap()
+        .region("comment", r"/\*", r"\*/", None).unwrap()
+        .region("string", "\"", "\"", Some(r"\\.")).unwrap()
+        .region_highlighter(r"\\.", &[(0, "meta")]).unwrap()
+        .keywords("keyword", &["if", "else"]).unwrap();
+
+    assert_eq!(
+        faces_of_line(&grammar, r#"if "a\"b" else"#, None),
+        vec![
+            ("if", "keyword".to_string()),
+            (r#""a\"b""#, "string".to_string()),
+            (r#"\""#, "meta".to_string()),
+            ("else", "keyword".to_string()),
+        ],
+    );
+
+    // Comments reach over lines, and keywords aren't highlighted inside them
+    let (_, end_region) = grammar.highlight_line("if /* else", None);
+    assert_eq!(end_region, Some(0));
+    assert_eq!(faces_of_line(&grammar, "else */ if", end_region), vec![("else */", "comment".to_string()), ("if", "keyword".to_string())]);
+
+    assert!(grammar.matches_path(Path::new("/tmp/a.test")));
+}
+
+#[test]
+fn test_highlighter_invalidation() {
+    let grammar = Rc::new(Grammar::new("test", "").unwrap().region("comment", r"/\*", r"\*/", None).unwrap());
+    let faces = Faces::builtin();
+    let mut highlighter = Highlighter::new(Some(grammar));
+
+    let mut content = Rope::from("a\nb\nc");
+    highlighter.highlights(&content, 0..3, &faces);
+
+    content.insert(0, "/*");
+    highlighter.invalidate_from(0);
+    let ranges = highlighter.highlights(&content, 2..3, &faces);
+    assert_eq!(ranges.face_at(content.len_bytes() - 1, Default::default()), faces.get("comment"));
+}
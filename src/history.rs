@@ -0,0 +1,263 @@
+// Undo tree. Every group of edits, such as everything typed in one insert mode
+// session, becomes a revision whose parent is the revision it was made on top
+// of. Undoing moves to the parent, and making new edits after undoing starts a
+// new branch instead of throwing away the undone revisions.
+
+use crate::rope::Rope;
+use crate::selection::SelectionSet;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Edit {
+    Insert { at: usize, text: String },
+    Remove { at: usize, text: String },
+}
+
+impl Edit {
+    fn apply(&self, content: &mut Rope) {
+        match self {
+            Edit::Insert { at, text } => content.insert(*at, text),
+            Edit::Remove { at, text } => content.remove(*at..*at + text.len()),
+        }
+    }
+
+    fn at(&self) -> usize {
+        match self {
+            Edit::Insert { at, .. } | Edit::Remove { at, .. } => *at,
+        }
+    }
+
+    fn inverse(&self) -> Edit {
+        match self {
+            Edit::Insert { at, text } => Edit::Remove { at: *at, text: text.clone() },
+            Edit::Remove { at, text } => Edit::Insert { at: *at, text: text.clone() },
+        }
+    }
+}
+
+struct Revision {
+    parent: usize,
+    // The child which redo moves to, i.e. the one most recently undone or created
+    redo_child: Option<usize>,
+    edits: Vec<Edit>,
+    selections_before: SelectionSet,
+    selections_after: SelectionSet,
+}
+
+struct Group {
+    edits: Vec<Edit>,
+    selections_before: SelectionSet,
+}
+
+pub struct History {
+    // revisions[0] is the root, which has no edits and is its own parent
+    revisions: Vec<Revision>,
+    current: usize,
+    group: Option<Group>,
+    // The first byte changed by undoing or redoing since take_changed_from was last called
+    changed_from: Option<usize>,
+}
+
+impl History {
+    pub fn new(selections: SelectionSet) -> Self {
+        Self {
+            revisions: vec![Revision {
+                parent: 0,
+                redo_child: None,
+                edits: Vec::new(),
+                selections_before: selections.clone(),
+                selections_after: selections,
+            }],
+            current: 0,
+            group: None,
+            changed_from: None,
+        }
+    }
+
+    /// Id of the revision the buffer is currently at
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    #[cfg(test)]
+    pub fn n_revisions(&self) -> usize {
+        self.revisions.len()
+    }
+
+    /// The first byte of the buffer which undoing or redoing has changed since the last call
+    pub fn take_changed_from(&mut self) -> Option<usize> {
+        self.changed_from.take()
+    }
+
+    /// Whether edits have been recorded in a group which hasn't ended yet
+    pub fn has_pending_edits(&self) -> bool {
+        self.group.as_ref().map(|group| !group.edits.is_empty()).unwrap_or(false)
+    }
+
+    /// Starts grouping edits into one revision, unless a group is already started
+    pub fn begin_group(&mut self, selections_before: &SelectionSet) {
+        if self.group.is_none() {
+            self.group = Some(Group {
+                edits: Vec::new(),
+                selections_before: selections_before.clone(),
+            });
+        }
+    }
+
+    /// Records an edit which has already been applied. Should be called inside a
+    /// group; otherwise one is started, with the selections of the current revision.
+    pub fn record(&mut self, edit: Edit) {
+        debug_assert!(self.group.is_some(), "Edit {:?} was made outside of an undo group", edit);
+        if self.group.is_none() {
+            let selections_before = self.revisions[self.current].selections_after.clone();
+            self.begin_group(&selections_before);
+        }
+        if let Some(group) = &mut self.group {
+            group.edits.push(edit);
+        }
+    }
+
+    /// Turns the edits recorded since begin_group into a new revision, if there were any
+    pub fn end_group(&mut self, selections_after: &SelectionSet) {
+        let group = if let Some(group) = self.group.take() {
+            group
+        } else {
+            return;
+        };
+        if group.edits.is_empty() {
+            return;
+        }
+
+        self.revisions.push(Revision {
+            parent: self.current,
+            redo_child: None,
+            edits: group.edits,
+            selections_before: group.selections_before,
+            selections_after: selections_after.clone(),
+        });
+        let new = self.revisions.len() - 1;
+        self.revisions[self.current].redo_child = Some(new);
+        self.current = new;
+    }
+
+    /// Moves to the parent revision. Returns the selections from before the undone
+    /// revision, or None if there is nothing to undo.
+    pub fn undo(&mut self, content: &mut Rope) -> Option<SelectionSet> {
+        if self.current == 0 {
+            return None;
+        }
+
+        let revision = &self.revisions[self.current];
+        for edit in revision.edits.iter().rev() {
+            edit.inverse().apply(content);
+            self.changed_from = Some(self.changed_from.map_or(edit.at(), |from| from.min(edit.at())));
+        }
+        let selections = revision.selections_before.clone();
+
+        let undone = self.current;
+        self.current = revision.parent;
+        self.revisions[self.current].redo_child = Some(undone);
+
+        Some(selections)
+    }
+
+    /// Moves to the most recently undone child revision. Returns the selections
+    /// after it, or None if there is nothing to redo.
+    pub fn redo(&mut self, content: &mut Rope) -> Option<SelectionSet> {
+        let child = self.revisions[self.current].redo_child?;
+
+        let revision = &self.revisions[child];
+        for edit in &revision.edits {
+            edit.apply(content);
+            self.changed_from = Some(self.changed_from.map_or(edit.at(), |from| from.min(edit.at())));
+        }
+        self.current = child;
+
+        Some(revision.selections_after.clone())
+    }
+
+    // The revision and all its ancestors, ending with the root
+    fn ancestors(&self, mut revision: usize) -> Vec<usize> {
+        let mut ancestors = vec![revision];
+        while revision != 0 {
+            revision = self.revisions[revision].parent;
+            ancestors.push(revision);
+        }
+        ancestors
+    }
+
+    /// Moves to any revision, undoing up to the common ancestor and redoing
+    /// down from there. Returns the selections at the target, or None if there
+    /// is no such revision.
+    pub fn jump_to(&mut self, target: usize, content: &mut Rope) -> Option<SelectionSet> {
+        if target >= self.revisions.len() {
+            return None;
+        }
+
+        let from_current = self.ancestors(self.current);
+        let from_target = self.ancestors(target);
+        let common = *from_target.iter().find(|revision| from_current.contains(revision))?;
+
+        let mut selections = self.revisions[self.current].selections_after.clone();
+        while self.current != common {
+            selections = self.undo(content)?;
+        }
+
+        let path_down: Vec<usize> = from_target.into_iter().take_while(|&revision| revision != common).collect();
+        for &revision in path_down.iter().rev() {
+            self.revisions[self.current].redo_child = Some(revision);
+            selections = self.redo(content)?;
+        }
+
+        Some(selections)
+    }
+}
+
+#[cfg(test)]
+fn edit(content: &mut Rope, history: &mut History, edit: Edit) {
+    edit.apply(content);
+    history.record(edit);
+}
+
+#[test]
+fn test_undo_tree() {
+    use crate::selection::Selection;
+
+    let sels = SelectionSet::new(Selection::cursor(0));
+    let mut content = Rope::from("ilo");
+    let mut history = History::new(sels.clone());
+
+    history.begin_group(&sels);
+    edit(&mut content, &mut history, Edit::Insert { at: 0, text: "ni ".to_string() });
+    edit(&mut content, &mut history, Edit::Insert { at: 3, text: "li ".to_string() });
+    history.end_group(&sels);
+    assert_eq!(content.to_string(), "ni li ilo");
+    let first = history.current();
+
+    history.begin_group(&sels);
+    edit(&mut content, &mut history, Edit::Remove { at: 0, text: "ni ".to_string() });
+    history.end_group(&sels);
+    assert_eq!(content.to_string(), "li ilo");
+
+    history.undo(&mut content);
+    assert_eq!(content.to_string(), "ni li ilo");
+    history.undo(&mut content);
+    assert_eq!(content.to_string(), "ilo");
+    history.redo(&mut content);
+    assert_eq!(content.to_string(), "ni li ilo");
+
+    // A new edit branches off instead of replacing the undone one
+    history.begin_group(&sels);
+    edit(&mut content, &mut history, Edit::Insert { at: 9, text: " pona".to_string() });
+    history.end_group(&sels);
+    assert_eq!(content.to_string(), "ni li ilo pona");
+    assert_eq!(history.n_revisions(), 4);
+
+    history.jump_to(2, &mut content);
+    assert_eq!(content.to_string(), "li ilo");
+    history.jump_to(3, &mut content);
+    assert_eq!(content.to_string(), "ni li ilo pona");
+    history.jump_to(0, &mut content);
+    assert_eq!(content.to_string(), "ilo");
+    history.jump_to(first, &mut content);
+    assert_eq!(content.to_string(), "ni li ilo");
+}
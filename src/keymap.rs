@@ -0,0 +1,218 @@
+// Key mappings. Each mode has a keymap from sequences of keys to either other
+// keys or a command line. Keys which aren't mapped do what they do by default.
+//
+// Keys are written as the char they type, or with a name in angle brackets:
+// <esc>, <ret>, <tab>, <space>, <backspace>, <left>, <right>, <up>, <down>,
+// <pageup>, <pagedown>, <lt> and <gt>. Chars typed with ctrl or alt held are
+// written <c-x> and <a-x>.
+
+use std::collections::HashMap;
+
+use crate::state::{Key, Mode};
+
+const NAMED_KEYS: &[(&str, Key)] = &[
+    ("esc", Key::Escape),
+    ("ret", Key::Typed('\n')),
+    ("tab", Key::Typed('\t')),
+    ("space", Key::Typed(' ')),
+    ("backspace", Key::Backspace),
+    ("left", Key::ArrowLeft),
+    ("right", Key::ArrowRight),
+    ("up", Key::ArrowUp),
+    ("down", Key::ArrowDown),
+    ("pageup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+    ("lt", Key::Typed('<')),
+    ("gt", Key::Typed('>')),
+];
+
+// The char of a key name inside <c-...> or <a-...>
+fn char_of_name(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) => Some(ch),
+        _ => NAMED_KEYS.iter().find_map(|&(key_name, key)| match key {
+            Key::Typed(ch) if key_name == name => Some(ch),
+            _ => None,
+        }),
+    }
+}
+
+/// Parses a sequence of keys, like "<c-x>gg<esc>"
+pub fn parse_keys(text: &str) -> Result<Vec<Key>, String> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+    while let Some(ch) = rest.chars().next() {
+        if ch != '<' {
+            keys.push(Key::Typed(ch));
+            rest = &rest[ch.len_utf8()..];
+            continue;
+        }
+
+        let close = rest.find('>').ok_or_else(|| format!("Unclosed < in {}, use <lt> for <", text))?;
+        let name = &rest[1..close];
+        let key = if let Some(ch) = name.strip_prefix("c-").and_then(char_of_name) {
+            Key::Ctrl(ch)
+        } else if let Some(ch) = name.strip_prefix("a-").and_then(char_of_name) {
+            Key::Alt(ch)
+        } else {
+            NAMED_KEYS
+                .iter()
+                .find(|(key_name, _)| *key_name == name)
+                .map(|&(_, key)| key)
+                .ok_or_else(|| format!("Unknown key <{}>", name))?
+        };
+        keys.push(key);
+        rest = &rest[close + 1..];
+    }
+    Ok(keys)
+}
+
+/// Writes keys the way parse_keys reads them
+pub fn keys_to_string(keys: &[Key]) -> String {
+    let char_name = |ch: char| match NAMED_KEYS.iter().find(|&&(_, key)| key == Key::Typed(ch)) {
+        Some((name, _)) => name.to_string(),
+        None => ch.to_string(),
+    };
+
+    let mut text = String::new();
+    for &key in keys {
+        match key {
+            Key::Typed(ch) => match char_name(ch) {
+                name if name.chars().count() > 1 => text.push_str(&format!("<{}>", name)),
+                name => text.push_str(&name),
+            },
+            Key::Ctrl(ch) => text.push_str(&format!("<c-{}>", char_name(ch))),
+            Key::Alt(ch) => text.push_str(&format!("<a-{}>", char_name(ch))),
+            _ => {
+                let name = NAMED_KEYS.iter().find(|&&(_, named)| named == key).map(|(name, _)| *name).unwrap_or("?");
+                text.push_str(&format!("<{}>", name));
+            }
+        }
+    }
+    text
+}
+
+pub fn parse_mode(name: &str) -> Result<Mode, String> {
+    match name {
+        "normal" => Ok(Mode::Normal),
+        "insert" => Ok(Mode::Insert),
+        "command" => Ok(Mode::Command),
+        _ => Err(format!("Unknown mode {}, expected normal, insert or command", name)),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mapping {
+    /// Keys which are handled as if they were typed, without being mapped again
+    Keys(Vec<Key>),
+    /// A command line which is run
+    Command(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Binding {
+    pub mapping: Mapping,
+    /// What the mapping does, shown while its keys are being typed
+    pub docstring: Option<String>,
+}
+
+impl Binding {
+    /// The docstring, or what the mapping is mapped to
+    pub fn description(&self) -> String {
+        match (&self.docstring, &self.mapping) {
+            (Some(docstring), _) => docstring.clone(),
+            (None, Mapping::Keys(keys)) => keys_to_string(keys),
+            (None, Mapping::Command(command)) => format!(":{}", command),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Keymap {
+    bindings: HashMap<Vec<Key>, Binding>,
+}
+
+impl Keymap {
+    pub fn map(&mut self, keys: Vec<Key>, binding: Binding) {
+        self.bindings.insert(keys, binding);
+    }
+
+    /// Removes a mapping, returning whether there was one
+    pub fn unmap(&mut self, keys: &[Key]) -> bool {
+        self.bindings.remove(keys).is_some()
+    }
+
+    pub fn get(&self, keys: &[Key]) -> Option<&Binding> {
+        self.bindings.get(keys)
+    }
+
+    /// Whether there are mappings which start with the keys and are longer
+    pub fn has_longer(&self, keys: &[Key]) -> bool {
+        self.bindings.keys().any(|mapped| mapped.len() > keys.len() && mapped.starts_with(keys))
+    }
+
+    /// The keys which can come after a prefix to make a mapping, and what they do, sorted by keys
+    pub fn completions(&self, prefix: &[Key]) -> Vec<(String, String)> {
+        let mut completions: Vec<(String, String)> = self.bindings
+            .iter()
+            .filter(|(mapped, _)| mapped.len() > prefix.len() && mapped.starts_with(prefix))
+            .map(|(mapped, binding)| (keys_to_string(&mapped[prefix.len()..]), binding.description()))
+            .collect();
+        completions.sort();
+        completions
+    }
+}
+
+/// The keymap of each mode
+#[derive(Clone, Debug, Default)]
+pub struct Keymaps {
+    normal: Keymap,
+    insert: Keymap,
+    command: Keymap,
+}
+
+impl Keymaps {
+    /// The mappings rakoune has without any configuration
+    pub fn builtin() -> Self {
+        let mut keymaps = Keymaps::default();
+        let command = |command: &str| Binding { mapping: Mapping::Command(command.to_string()), docstring: None };
+
+        for &mode in &[Mode::Normal, Mode::Insert, Mode::Command] {
+            let keymap = keymaps.get_mut(mode);
+            keymap.map(vec![Key::Ctrl('=')], command("zoom in"));
+            keymap.map(vec![Key::Ctrl('+')], command("zoom in"));
+            keymap.map(vec![Key::Ctrl('-')], command("zoom out"));
+            keymap.map(vec![Key::Ctrl('0')], command("zoom reset"));
+        }
+        keymaps.normal.map(vec![Key::Typed('\t')], command("debug-dump"));
+        keymaps
+    }
+
+    pub fn get(&self, mode: Mode) -> &Keymap {
+        match mode {
+            Mode::Normal => &self.normal,
+            Mode::Insert => &self.insert,
+            Mode::Command => &self.command,
+        }
+    }
+
+    pub fn get_mut(&mut self, mode: Mode) -> &mut Keymap {
+        match mode {
+            Mode::Normal => &mut self.normal,
+            Mode::Insert => &mut self.insert,
+            Mode::Command => &mut self.command,
+        }
+    }
+}
+
+#[test]
+fn test_parse_keys() {
+    let keys = parse_keys("<c-=>g<a-space><lt><esc>").unwrap();
+    assert_eq!(keys, vec![Key::Ctrl('='), Key::Typed('g'), Key::Alt(' '), Key::Typed('<'), Key::Escape]);
+    assert_eq!(keys_to_string(&keys), "<c-=>g<a-space><lt><esc>");
+    assert_eq!(keys_to_string(&parse_keys("<ret> <tab>").unwrap()), "<ret><space><tab>");
+
+    assert_eq!(parse_keys("<c-x"), Err("Unclosed < in <c-x, use <lt> for <".to_string()));
+    assert_eq!(parse_keys("<escape>"), Err("Unknown key <escape>".to_string()));
+}
@@ -0,0 +1,131 @@
+// Where text ends up horizontally when it's displayed. Vertical motions use this
+// to keep the cursor at the same display column, which isn't the same as the
+// same char column when lines contain ligatures or glyphs of different widths.
+
+/// Which fonts text is drawn with
+#[derive(Clone, Debug, PartialEq)]
+pub struct FontConfig {
+    /// Names of builtin or installed fonts, or paths to font files. The first
+    /// is the main font, and the others are fallbacks for chars it doesn't have.
+    pub fonts: Vec<String>,
+    pub size_px: f32,
+    /// How many times the text has been zoomed in, or out if negative
+    pub zoom: i32,
+}
+
+impl FontConfig {
+    // Smaller fonts can't be read, and larger glyphs don't fit in the glyph atlas
+    pub const MIN_SIZE_PX: f32 = 6.;
+    pub const MAX_SIZE_PX: f32 = 160.;
+    // How much each zoom step scales the text
+    const ZOOM_FACTOR: f32 = 1.125;
+
+    /// The size text is drawn at
+    pub fn zoomed_size_px(&self) -> f32 {
+        (self.size_px * Self::ZOOM_FACTOR.powi(self.zoom)).round().clamp(Self::MIN_SIZE_PX, Self::MAX_SIZE_PX)
+    }
+
+    /// Zooms in by a number of steps, or out if negative. Zooming stops at the smallest and largest sizes.
+    pub fn zoom_by(&mut self, steps: i32) {
+        let old_size = self.zoomed_size_px();
+        self.zoom += steps;
+        if self.zoomed_size_px() == old_size {
+            self.zoom -= steps;
+        }
+    }
+}
+
+impl Default for FontConfig {
+    fn default() -> Self {
+        Self {
+            fonts: vec!["Fira Code".to_string(), "linja pona".to_string()],
+            size_px: 24.,
+            zoom: 0,
+        }
+    }
+}
+
+/// How the text of a buffer is turned into glyphs
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Shaping {
+    /// With the main font, falling back to other fonts for chars it doesn't have
+    Plain,
+    /// With linja pona first, whose ligatures turn toki pona words into sitelen pona glyphs
+    SitelenPona,
+}
+
+/// Measures lines of text the way the renderer lays them out
+pub trait LineLayout {
+    /// The x position in pixels of the start of every cluster in the line, as
+    /// (byte offset in line, x), sorted by byte offset. Also contains the end of
+    /// the line, at byte offset line.len().
+    fn cluster_positions(&self, line: &str, shaping: Shaping) -> Vec<(usize, f32)>;
+}
+
+/// Every char is equally wide. Used until the renderer provides the real layout.
+pub struct Monospace {
+    pub advance: f32,
+}
+
+impl LineLayout for Monospace {
+    fn cluster_positions(&self, line: &str, _shaping: Shaping) -> Vec<(usize, f32)> {
+        line.char_indices()
+            .map(|(byte, _)| byte)
+            .chain(std::iter::once(line.len()))
+            .enumerate()
+            .map(|(char_idx, byte)| (byte, char_idx as f32 * self.advance))
+            .collect()
+    }
+}
+
+/// The x position where the byte is displayed. Bytes inside a cluster are at the start of the cluster.
+pub fn x_of_byte(layout: &dyn LineLayout, shaping: Shaping, line: &str, byte: usize) -> f32 {
+    layout.cluster_positions(line, shaping)
+        .into_iter()
+        .take_while(|&(start, _)| start <= byte)
+        .last()
+        .map(|(_, x)| x)
+        .unwrap_or(0.)
+}
+
+/// The byte offset of the cluster boundary closest to x
+pub fn byte_at_x(layout: &dyn LineLayout, shaping: Shaping, line: &str, x: f32) -> usize {
+    layout.cluster_positions(line, shaping)
+        .into_iter()
+        .min_by(|(_, a), (_, b)| (a - x).abs().partial_cmp(&(b - x).abs()).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(byte, _)| byte)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+struct Ligatures;
+
+// "->" is one cluster as wide as one char, other chars are 10 pixels wide
+#[cfg(test)]
+impl LineLayout for Ligatures {
+    fn cluster_positions(&self, line: &str, _shaping: Shaping) -> Vec<(usize, f32)> {
+        let mut positions = Vec::new();
+        let mut byte = 0;
+        let mut x = 0.;
+        while byte < line.len() {
+            positions.push((byte, x));
+            byte += if line[byte..].starts_with("->") { 2 } else { line[byte..].chars().next().unwrap().len_utf8() };
+            x += 10.;
+        }
+        positions.push((line.len(), x));
+        positions
+    }
+}
+
+#[test]
+fn test_layout_positions() {
+    let line = "a->b";
+    assert_eq!(x_of_byte(&Ligatures, Shaping::Plain, line, 2), 10.);
+    assert_eq!(x_of_byte(&Ligatures, Shaping::Plain, line, 3), 20.);
+    assert_eq!(x_of_byte(&Ligatures, Shaping::Plain, line, 4), 30.);
+
+    assert_eq!(byte_at_x(&Ligatures, Shaping::Plain, line, 14.), 1);
+    assert_eq!(byte_at_x(&Ligatures, Shaping::Plain, line, 16.), 3);
+    assert_eq!(byte_at_x(&Ligatures, Shaping::Plain, line, 100.), 4);
+    assert_eq!(byte_at_x(&Monospace { advance: 10. }, Shaping::Plain, "åäö", 12.), 2);
+}
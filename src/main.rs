@@ -1,9 +1,24 @@
 #![feature(async_closure)]
 
 mod render;
+mod command;
+mod config;
+mod highlight;
+mod history;
+mod keymap;
+mod layout;
+mod motion;
+mod options;
+mod rope;
+mod selection;
 mod state;
+mod status;
+mod style;
+mod theme;
+mod viewport;
 
 use std::io::{Result as IOResult, Error, ErrorKind};
+use std::path::PathBuf;
 use std::time::{Instant, Duration};
 
 use futures::executor::block_on;
@@ -12,11 +27,14 @@ use winit::{
     event_loop::{EventLoop, ControlFlow},
     window::{WindowBuilder, Window},
 
-    event::{Event, WindowEvent, KeyboardInput, VirtualKeyCode, ElementState},
+    event::{Event, WindowEvent, KeyboardInput, VirtualKeyCode, ElementState, ModifiersState, MouseScrollDelta},
 };
 
 use render::RenderState;
 use state::State;
+use rope::Rope;
+
+const SCROLL_LINES_PER_NOTCH: f32 = 3.;
 
 pub fn into_ioerror<T: ToString>(x: T) -> Error {
     Error::new(
@@ -37,11 +55,29 @@ fn main() -> IOResult<()> {
     render_state.resize(window.inner_size()).expect("Window resize failed");
 
 
-    let mut state = State::new("ni li ilo pi pana sitelen".to_string());
+    let mut state = State::new(Rope::new());
+    // Before the rakrc, which can set the options of the renderer
+    render_state.subscribe_to_options(&mut state.options);
+    let rakrc_result = config::rakrc_path().filter(|path| path.exists()).map(|path| state.source(&path));
+    if let Some(path) = std::env::args_os().nth(1).map(PathBuf::from) {
+        // If the file can't be opened, the buffer stays a scratch buffer so the file isn't overwritten by accident
+        let result = state.edit(&path, true);
+        state.report(result);
+    }
+    // Errors in the rakrc are shown over the message of the file
+    if let Some(Err(e)) = rakrc_result {
+        state.report(Err(e));
+    }
+    update_fonts(&mut render_state, &mut state);
+    let mut title = String::new();
 
     let mut frame_instants: Vec<Instant> = Vec::new();
     let mut frame_durations: Vec<Duration> = Vec::new();
     let mut last_debug_time: Option<Instant> = None;
+    // When the state was last stepped, so that it's stepped by the time which really passed
+    let mut last_step = Instant::now();
+
+    let mut modifiers = ModifiersState::empty();
 
     el.run(move |event, _, cf| {
         if last_debug_time.map(|ldt| Instant::now() - ldt > Duration::new(2, 0)).unwrap_or(true) {
@@ -72,7 +108,13 @@ fn main() -> IOResult<()> {
             Event::WindowEvent {
                 event: w_event, ..
             } => {
-                block_on(handle_window_event(w_event, &mut window, cf, &mut render_state, &mut state));
+                block_on(handle_window_event(w_event, &mut window, cf, &mut render_state, &mut state, &mut modifiers));
+                apply_requests(&mut render_state, &mut state, cf);
+
+                if state.title() != title {
+                    title = state.title();
+                    window.set_title(&title);
+                }
             }
             Event::MainEventsCleared => {
                 // RedrawRequested will only trigger once, unless we manually
@@ -82,9 +124,16 @@ fn main() -> IOResult<()> {
             Event::RedrawRequested(_) => {
                 frame_instants.push(Instant::now());
 
-                state.step(1. / 60.); // Assume 60 FPS
+                let now = Instant::now();
+                state.step((now - last_step).as_secs_f32());
+                last_step = now;
+                // Mappings can run when keys time out
+                apply_requests(&mut render_state, &mut state, cf);
                 let render_start = Instant::now();
                 block_on(render_state.render(&state)).expect("Render error");
+                if render_state.take_atlas_filled() {
+                    state.report(Err("The glyph atlas is full, some glyphs were not drawn".to_string()));
+                }
                 frame_durations.push(Instant::now() - render_start);
             }
             _ => {}
@@ -92,10 +141,54 @@ fn main() -> IOResult<()> {
     });
 }
 
-async fn handle_window_event(w_event: WindowEvent<'_>, _window: &mut Window, cf: &mut ControlFlow, render_state: &mut RenderState, state: &mut State) {
+// Does what commands have asked of the window and the renderer
+fn apply_requests(render_state: &mut RenderState, state: &mut State, cf: &mut ControlFlow) {
+    if state.font != *render_state.font_config() {
+        update_fonts(render_state, state);
+    }
+    if state.debug_dump_requested {
+        state.debug_dump_requested = false;
+        block_on(render_state.dump_debug()).expect("Debug dump failed");
+    }
+    if state.quit_requested {
+        *cf = ControlFlow::Exit;
+    }
+}
+
+// Loads the fonts the editor asks for, keeping the old ones if they can't be
+// loaded, and lays out the editor for them. The line of the primary cursor
+// stays where it was in the window, so zooming doesn't lose your place.
+fn update_fonts(render_state: &mut RenderState, state: &mut State) {
+    let cursor_line = state.content.byte_to_line(state.selections.primary().cursor);
+    let cursor_y = state.viewport.y_of_line(cursor_line);
+
+    if state.font != *render_state.font_config() {
+        // The font option refuses fonts which can't be loaded, so this only fails
+        // if the font files changed after the option was set
+        if let Err(e) = render_state.set_font_config(&state.font) {
+            state.report(Err(format!("Couldn't load fonts: {}", e)));
+            state.font = render_state.font_config().clone();
+        }
+    }
+
+    state.layout = render_state.line_layout();
+    render_state.set_viewport_size(&mut state.viewport);
+    state.viewport.scroll_line_to_y(cursor_line, cursor_y);
+    state.update_viewport();
+}
+
+async fn handle_window_event(
+    w_event: WindowEvent<'_>,
+    _window: &mut Window,
+    _cf: &mut ControlFlow,
+    render_state: &mut RenderState,
+    state: &mut State,
+    modifiers: &mut ModifiersState,
+) {
     match w_event {
         WindowEvent::CloseRequested => {
-            *cf = ControlFlow::Exit;
+            let result = state.quit(false);
+            state.report(result);
         }
         WindowEvent::Resized(phys_size) |
         WindowEvent::ScaleFactorChanged {
@@ -103,65 +196,92 @@ async fn handle_window_event(w_event: WindowEvent<'_>, _window: &mut Window, cf:
             ..
         } => {
             render_state.resize(phys_size).expect("Window resize failed");
+            render_state.set_viewport_size(&mut state.viewport);
+            state.update_viewport();
         }
         WindowEvent::KeyboardInput {
             input: KeyboardInput {
-                virtual_keycode: Some(VirtualKeyCode::Escape),
-                state: ElementState::Pressed,
-                ..
-            },
-            ..
-        } => {
-            *cf = ControlFlow::Exit;
-        }
-        WindowEvent::KeyboardInput {
-            input: KeyboardInput {
-                virtual_keycode: Some(VirtualKeyCode::Tab),
-                state: ElementState::Pressed,
-                ..
-            },
-            ..
-        } => {
-            render_state.dump_debug().await.expect("Debug dump failed");
-        }
-        WindowEvent::KeyboardInput {
-            input: KeyboardInput {
-                virtual_keycode: Some(VirtualKeyCode::Left),
+                virtual_keycode: Some(keycode),
                 state: ElementState::Pressed,
                 ..
             },
             ..
         } => {
-            state.received_key(state::Key::ArrowLeft);
+            if let Some(key) = key_of(keycode, *modifiers) {
+                state.received_key(key);
+            }
         }
-        WindowEvent::KeyboardInput {
-            input: KeyboardInput {
-                virtual_keycode: Some(VirtualKeyCode::Right),
-                state: ElementState::Pressed,
-                ..
-            },
-            ..
-        } => {
-            state.received_key(state::Key::ArrowRight);
+        WindowEvent::MouseWheel { delta, .. } => {
+            let line_height = state.viewport.line_height();
+            let (lines, px) = match delta {
+                MouseScrollDelta::LineDelta(x, y) => (-y * SCROLL_LINES_PER_NOTCH, -x * line_height),
+                MouseScrollDelta::PixelDelta(pos) => (-pos.y as f32 / line_height, -pos.x as f32),
+            };
+            state.scroll(lines, px);
         }
-        WindowEvent::KeyboardInput {
-            input: KeyboardInput {
-                virtual_keycode: Some(VirtualKeyCode::Back),
-                state: ElementState::Pressed,
-                ..
-            },
-            ..
-        } => {
-            state.received_key(state::Key::Backspace);
+        WindowEvent::ModifiersChanged(new_modifiers) => {
+            *modifiers = new_modifiers;
         }
         WindowEvent::ReceivedCharacter(mut ch) => {
             if ch == '\r' {
                 ch = '\n';
             }
-            if !ch.is_control() || ch == '\n' {
+            if modifiers.ctrl() && !modifiers.alt() {
+                // Chars typed with ctrl are handled as key presses, see key_of
+            } else if modifiers.alt() && !ch.is_control() {
+                state.received_key(state::Key::Alt(ch));
+            } else if !ch.is_control() || ch == '\n' || ch == '\t' {
                 state.received_key(state::Key::Typed(ch));
             }
         }
         _ => {}
     }
 }
+
+// The key of a key press, for keys which don't type a char and for chars typed
+// with ctrl. Other chars are handled when they are received.
+fn key_of(keycode: VirtualKeyCode, modifiers: ModifiersState) -> Option<state::Key> {
+    use state::Key;
+    Some(match keycode {
+        VirtualKeyCode::Escape => Key::Escape,
+        VirtualKeyCode::Back => Key::Backspace,
+        VirtualKeyCode::Left => Key::ArrowLeft,
+        VirtualKeyCode::Right => Key::ArrowRight,
+        VirtualKeyCode::Up => Key::ArrowUp,
+        VirtualKeyCode::Down => Key::ArrowDown,
+        VirtualKeyCode::PageUp => Key::PageUp,
+        VirtualKeyCode::PageDown => Key::PageDown,
+        _ if modifiers.ctrl() && !modifiers.alt() => Key::Ctrl(char_of_keycode(keycode)?),
+        _ => return None,
+    })
+}
+
+// The char a key types without shift, or + and - for the keypad keys
+fn char_of_keycode(keycode: VirtualKeyCode) -> Option<char> {
+    use VirtualKeyCode::*;
+    const LETTERS: &[VirtualKeyCode] = &[A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z];
+    const DIGITS: &[VirtualKeyCode] = &[Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+
+    if let Some(idx) = LETTERS.iter().position(|&letter| letter == keycode) {
+        return Some((b'a' + idx as u8) as char);
+    }
+    if let Some(idx) = DIGITS.iter().position(|&digit| digit == keycode) {
+        return Some((b'0' + idx as u8) as char);
+    }
+    Some(match keycode {
+        Space => ' ',
+        Equals => '=',
+        Minus | Subtract => '-',
+        Add => '+',
+        Comma => ',',
+        Period => '.',
+        Slash => '/',
+        Backslash => '\\',
+        Semicolon => ';',
+        Apostrophe => '\'',
+        Grave => '`',
+        LBracket => '[',
+        RBracket => ']',
+        _ => return None,
+    })
+}
@@ -0,0 +1,120 @@
+// Functions for finding positions in the buffer, used by the normal mode commands.
+// All positions are byte indices.
+
+use crate::rope::Rope;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum CharClass {
+    Word,
+    Punctuation,
+    Whitespace,
+}
+
+fn char_class(ch: char) -> CharClass {
+    if ch.is_alphanumeric() || ch == '_' {
+        CharClass::Word
+    } else if ch.is_whitespace() {
+        CharClass::Whitespace
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+fn skip_forward(content: &Rope, mut pos: usize, pred: impl Fn(char) -> bool) -> usize {
+    while let Some(ch) = content.char_at(pos) {
+        if !pred(ch) {
+            break;
+        }
+        pos += ch.len_utf8();
+    }
+    pos
+}
+
+fn skip_backward(content: &Rope, mut pos: usize, pred: impl Fn(char) -> bool) -> usize {
+    while let Some(before) = content.prev_char_boundary(pos) {
+        if !content.char_at(before).map(&pred).unwrap_or(false) {
+            break;
+        }
+        pos = before;
+    }
+    pos
+}
+
+/// Start of the word after the one at pos
+pub fn next_word_start(content: &Rope, pos: usize) -> usize {
+    let pos = match content.char_at(pos) {
+        Some(ch) if char_class(ch) != CharClass::Whitespace => {
+            let class = char_class(ch);
+            skip_forward(content, pos, |ch| char_class(ch) == class)
+        }
+        _ => pos,
+    };
+    skip_forward(content, pos, |ch| char_class(ch) == CharClass::Whitespace)
+}
+
+/// End of the word at pos, or of the next word if pos is at whitespace or at the end of a word
+pub fn word_end(content: &Rope, pos: usize) -> usize {
+    let pos = skip_forward(content, pos, |ch| char_class(ch) == CharClass::Whitespace);
+    match content.char_at(pos) {
+        Some(ch) => {
+            let class = char_class(ch);
+            skip_forward(content, pos, |ch| char_class(ch) == class)
+        }
+        None => pos,
+    }
+}
+
+/// Start of the word before pos
+pub fn prev_word_start(content: &Rope, pos: usize) -> usize {
+    let pos = skip_backward(content, pos, |ch| char_class(ch) == CharClass::Whitespace);
+    match content.prev_char_boundary(pos).and_then(|before| content.char_at(before)) {
+        Some(ch) => {
+            let class = char_class(ch);
+            skip_backward(content, pos, |ch| char_class(ch) == class)
+        }
+        None => pos,
+    }
+}
+
+/// Moves pos the given number of lines, keeping the char column if the target line is long enough
+pub fn offset_lines(content: &Rope, pos: usize, line_offset: isize) -> usize {
+    let line = content.byte_to_line(pos);
+    let column = content.byte_to_char(pos) - content.byte_to_char(content.line_to_byte(line));
+
+    let target_line = (line as isize + line_offset).max(0).min(content.len_lines() as isize - 1) as usize;
+    let target = content.line_range(target_line);
+    let target_start = content.byte_to_char(target.start);
+    let target_end = content.byte_to_char(target.end);
+    content.char_to_byte((target_start + column).min(target_end))
+}
+
+#[test]
+fn test_word_motions() {
+    let content = Rope::from("ni li, ilo  pona\nsitelen");
+
+    assert_eq!(next_word_start(&content, 0), 3);
+    assert_eq!(next_word_start(&content, 3), 5);
+    assert_eq!(next_word_start(&content, 5), 7);
+    assert_eq!(next_word_start(&content, 7), 12);
+    assert_eq!(next_word_start(&content, 12), 17);
+
+    assert_eq!(word_end(&content, 0), 2);
+    assert_eq!(word_end(&content, 2), 5);
+    assert_eq!(word_end(&content, 16), 24);
+
+    assert_eq!(prev_word_start(&content, 17), 12);
+    assert_eq!(prev_word_start(&content, 12), 7);
+    assert_eq!(prev_word_start(&content, 6), 5);
+    assert_eq!(prev_word_start(&content, 2), 0);
+    assert_eq!(prev_word_start(&content, 0), 0);
+}
+
+#[test]
+fn test_offset_lines() {
+    let content = Rope::from("första\nab\nlängre rad");
+
+    assert_eq!(offset_lines(&content, 5, 1), 10); // Clamped to the end of "ab"
+    assert_eq!(offset_lines(&content, 4, 2), content.char_to_byte(content.byte_to_char(11) + 3));
+    assert_eq!(offset_lines(&content, 9, -1), 1);
+    assert_eq!(offset_lines(&content, 9, -5), 1);
+}
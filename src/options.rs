@@ -0,0 +1,314 @@
+// Options are named settings with a type, such as the font size. They are set
+// in one of three scopes: global, the buffer or the window. An option set in
+// the window scope is used over one set for the buffer, which is used over the
+// global one, and options which aren't set anywhere have their default value.
+// Buffer options are forgotten when another file is edited.
+//
+// Parts of the editor which depend on options subscribe to them, and are told
+// the names of the options which changed.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::layout::FontConfig;
+use crate::status::{self, StatusLine};
+use crate::style::Color;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    Int { min: i64, max: i64 },
+    Bool,
+    Str,
+    List,
+    Color,
+    /// One of the names
+    Enum(Vec<String>),
+}
+
+impl Type {
+    /// Parses int, bool, str, list, color, or enum(a|b|c)
+    pub fn parse(text: &str) -> Result<Type, String> {
+        Ok(match text {
+            "int" => Type::Int { min: i64::MIN, max: i64::MAX },
+            "bool" => Type::Bool,
+            "str" => Type::Str,
+            "list" => Type::List,
+            "color" => Type::Color,
+            _ => match text.strip_prefix("enum(").and_then(|rest| rest.strip_suffix(')')) {
+                Some(names) => Type::Enum(names.split('|').map(str::to_string).collect()),
+                None => return Err(format!("Unknown option type {}, expected int, bool, str, list, color or enum(...)", text)),
+            },
+        })
+    }
+
+    /// Parses words into a value of the type, for the option with the name
+    pub fn parse_value(&self, name: &str, words: &[String]) -> Result<Value, String> {
+        // All types but lists take exactly one word
+        let word = || match words {
+            [word] => Ok(word.as_str()),
+            _ => Err(format!("{} takes one value", name)),
+        };
+
+        match self {
+            Type::Int { min, max } => {
+                let word = word()?;
+                let int: i64 = word.parse().map_err(|_| format!("Invalid number {}", word))?;
+                if int < *min || int > *max {
+                    return Err(format!("{} is between {} and {}", name, min, max));
+                }
+                Ok(Value::Int(int))
+            }
+            Type::Bool => match word()? {
+                "true" | "yes" | "on" => Ok(Value::Bool(true)),
+                "false" | "no" | "off" => Ok(Value::Bool(false)),
+                _ => Err(format!("{} is true or false", name)),
+            },
+            Type::Str => Ok(Value::Str(word()?.to_string())),
+            Type::List => Ok(Value::List(words.to_vec())),
+            Type::Color => Color::parse(word()?).map(Value::Color),
+            Type::Enum(names) => {
+                let word = word()?;
+                if names.iter().any(|enum_name| enum_name == word) {
+                    Ok(Value::Enum(word.to_string()))
+                } else {
+                    Err(format!("{} is one of {}", name, names.join(", ")))
+                }
+            }
+        }
+    }
+
+    fn accepts(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Type::Int { min, max }, Value::Int(int)) => min <= int && int <= max,
+            (Type::Enum(names), Value::Enum(name)) => names.contains(name),
+            (Type::Bool, Value::Bool(_)) | (Type::Str, Value::Str(_)) | (Type::List, Value::List(_)) | (Type::Color, Value::Color(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Str(String),
+    List(Vec<String>),
+    Color(Color),
+    Enum(String),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Global,
+    Buffer,
+    Window,
+}
+
+impl Scope {
+    pub fn parse(text: &str) -> Option<Scope> {
+        match text {
+            "global" => Some(Scope::Global),
+            "buffer" => Some(Scope::Buffer),
+            "window" => Some(Scope::Window),
+            _ => None,
+        }
+    }
+}
+
+// Checks values further than their type does
+type Check = fn(&Value) -> Result<(), String>;
+
+struct Declaration {
+    ty: Type,
+    default: Value,
+    check: Option<Check>,
+}
+
+/// The names of the options which changed since a subscriber last took them
+#[derive(Clone, Debug, Default)]
+pub struct Changes(Rc<RefCell<Vec<String>>>);
+
+impl Changes {
+    pub fn take(&self) -> Vec<String> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+#[derive(Default)]
+pub struct Options {
+    declarations: HashMap<String, Declaration>,
+    values: HashMap<(Scope, String), Value>,
+    subscribers: Vec<Weak<RefCell<Vec<String>>>>,
+}
+
+impl Options {
+    /// The options of the editor itself. Other parts of rakoune, like the renderer, declare their own.
+    pub fn builtin() -> Self {
+        let mut options = Options::default();
+        let font = FontConfig::default();
+        let declarations = vec![
+            ("line-numbers", Type::Enum(vec!["off".to_string(), "absolute".to_string(), "relative".to_string()]), Value::Enum("absolute".to_string())),
+            ("sitelen-pona", Type::Bool, Value::Bool(false)),
+            ("font", Type::List, Value::List(font.fonts)),
+            ("font-size", Type::Int { min: FontConfig::MIN_SIZE_PX as i64, max: FontConfig::MAX_SIZE_PX as i64 }, Value::Int(font.size_px as i64)),
+            // In milliseconds
+            ("key-timeout", Type::Int { min: 0, max: 60_000 }, Value::Int(1000)),
+            ("status-line", Type::Str, Value::Str(status::DEFAULT_FORMAT.to_string())),
+        ];
+        for (name, ty, default) in declarations {
+            options.declare(name, ty, default).expect("Builtin options are valid");
+        }
+        options.set_check("status-line", |value| match value {
+            Value::Str(format) => StatusLine::parse(format).map(|_| ()),
+            _ => Ok(()),
+        }).expect("status-line was declared");
+        options
+    }
+
+    pub fn declare(&mut self, name: &str, ty: Type, default: Value) -> Result<(), String> {
+        if self.declarations.contains_key(name) {
+            return Err(format!("Option {} is already declared", name));
+        }
+        if !ty.accepts(&default) {
+            return Err(format!("Invalid default value for {}", name));
+        }
+        self.declarations.insert(name.to_string(), Declaration { ty, default, check: None });
+        Ok(())
+    }
+
+    /// Makes setting an option fail for values which check refuses, beyond what their type allows
+    pub fn set_check(&mut self, name: &str, check: Check) -> Result<(), String> {
+        let declaration = self.declarations.get_mut(name).ok_or_else(|| format!("Unknown option {}", name))?;
+        declaration.check = Some(check);
+        Ok(())
+    }
+
+    fn declaration(&self, name: &str) -> Result<&Declaration, String> {
+        self.declarations.get(name).ok_or_else(|| format!("Unknown option {}", name))
+    }
+
+    /// Parses the words of a set-option command into a value for the option
+    pub fn parse_value(&self, name: &str, words: &[String]) -> Result<Value, String> {
+        self.declaration(name)?.ty.parse_value(name, words)
+    }
+
+    pub fn set(&mut self, scope: Scope, name: &str, value: Value) -> Result<(), String> {
+        let declaration = self.declaration(name)?;
+        if !declaration.ty.accepts(&value) {
+            return Err(format!("Invalid value for {}", name));
+        }
+        if let Some(check) = declaration.check {
+            check(&value)?;
+        }
+        self.values.insert((scope, name.to_string()), value);
+        self.notify(name);
+        Ok(())
+    }
+
+    /// Removes the value of an option in a scope, so that the one of the scope below is used
+    pub fn unset(&mut self, scope: Scope, name: &str) -> Result<(), String> {
+        self.declaration(name)?;
+        if self.values.remove(&(scope, name.to_string())).is_some() {
+            self.notify(name);
+        }
+        Ok(())
+    }
+
+    /// Unsets all options in a scope, e.g. when the buffer is replaced
+    pub fn clear_scope(&mut self, scope: Scope) {
+        let names: Vec<String> = self.values.keys().filter(|(value_scope, _)| *value_scope == scope).map(|(_, name)| name.clone()).collect();
+        for name in names {
+            self.values.remove(&(scope, name.clone()));
+            self.notify(&name);
+        }
+    }
+
+    /// The value of an option in the innermost scope it is set in. Panics if the
+    /// option isn't declared, since only declared options should be looked up.
+    pub fn get(&self, name: &str) -> &Value {
+        [Scope::Window, Scope::Buffer, Scope::Global]
+            .iter()
+            .find_map(|&scope| self.values.get(&(scope, name.to_string())))
+            .unwrap_or_else(|| &self.declarations.get(name).unwrap_or_else(|| panic!("Unknown option {}", name)).default)
+    }
+
+    pub fn int(&self, name: &str) -> i64 {
+        match self.get(name) {
+            Value::Int(int) => *int,
+            _ => panic!("{} isn't an int", name),
+        }
+    }
+
+    pub fn bool(&self, name: &str) -> bool {
+        match self.get(name) {
+            Value::Bool(value) => *value,
+            _ => panic!("{} isn't a bool", name),
+        }
+    }
+
+    /// The value of a str or enum option
+    pub fn str(&self, name: &str) -> &str {
+        match self.get(name) {
+            Value::Str(text) | Value::Enum(text) => text,
+            _ => panic!("{} isn't a str or an enum", name),
+        }
+    }
+
+    pub fn list(&self, name: &str) -> &[String] {
+        match self.get(name) {
+            Value::List(list) => list,
+            _ => panic!("{} isn't a list", name),
+        }
+    }
+
+    /// Subscribes to changes of the options. A new subscriber is told about every
+    /// declared option at first, so it can start from their values.
+    pub fn subscribe(&mut self) -> Changes {
+        let changes = Changes(Rc::new(RefCell::new(self.declarations.keys().cloned().collect())));
+        self.subscribers.push(Rc::downgrade(&changes.0));
+        changes
+    }
+
+    fn notify(&mut self, name: &str) {
+        self.subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+        for subscriber in &self.subscribers {
+            if let Some(changes) = subscriber.upgrade() {
+                let mut changes = changes.borrow_mut();
+                if !changes.iter().any(|changed| changed == name) {
+                    changes.push(name.to_string());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_options() {
+    let mut options = Options::builtin();
+    let changes = options.subscribe();
+    assert!(changes.take().contains(&"font-size".to_string()));
+    assert_eq!(changes.take(), Vec::<String>::new());
+
+    let words = |words: &[&str]| words.iter().map(|word| word.to_string()).collect::<Vec<_>>();
+    let value = options.parse_value("font-size", &words(&["20"])).unwrap();
+    options.set(Scope::Global, "font-size", value).unwrap();
+    options.set(Scope::Buffer, "font-size", Value::Int(30)).unwrap();
+    assert_eq!(options.int("font-size"), 30);
+    assert_eq!(changes.take(), vec!["font-size".to_string()]);
+
+    // Buffer options are used over global ones until they are unset
+    options.clear_scope(Scope::Buffer);
+    assert_eq!(options.int("font-size"), 20);
+    options.unset(Scope::Global, "font-size").unwrap();
+    assert_eq!(options.int("font-size"), FontConfig::default().size_px as i64);
+
+    assert_eq!(options.parse_value("font-size", &words(&["1000"])), Err("font-size is between 6 and 160".to_string()));
+    assert_eq!(options.parse_value("line-numbers", &words(&["sin"])), Err("line-numbers is one of off, absolute, relative".to_string()));
+    assert_eq!(options.set(Scope::Global, "status-line", Value::Str("{nasa}".to_string())), Err("Unknown status line field nasa".to_string()));
+    assert_eq!(options.parse_value("nasa", &[]), Err("Unknown option nasa".to_string()));
+
+    options.declare("accent", Type::parse("color").unwrap(), Value::Color(Color::rgb(1., 0., 0.))).unwrap();
+    assert!(options.parse_value("accent", &words(&["rgb:00ff00"])).is_ok());
+    assert_eq!(Type::parse("enum(a|b)"), Ok(Type::Enum(words(&["a", "b"]))));
+}
@@ -23,6 +23,7 @@ use image::GenericImageView;
 
 use super::{RenderBackend, RichTexture};
 use crate::into_ioerror;
+use crate::options::{Options, Type, Value};
 use crate::state::State;
 
 const LOGO_VERTEX_SHADER: &[u8] = include_bytes!("../../compiled-shaders/logo-vert.spv");
@@ -30,9 +31,13 @@ const LOGO_FRAGMENT_SHADER: &[u8] = include_bytes!("../../compiled-shaders/logo-
 
 const LOGO_IMAGE_PNG: &[u8] = include_bytes!("../../resources/rakoune_logo.png");
 
+// The uniform is the screen width and height, and the size of the logo, all in pixels
+const UNIFORM_SIZE: u64 = (3 * std::mem::size_of::<u32>()) as u64;
+
 pub(super) struct LogoRenderer {
     logo_render_pipeline: RenderPipeline,
-    screen_size_buffer: Buffer,
+    uniform_buffer: Buffer,
+    logo_size: u32,
     logo_bindgroup: wgpu::BindGroup,
     logo_texture: RichTexture,
 }
@@ -110,8 +115,9 @@ impl LogoRenderer {
         let logo_vs_module = backend.load_shader_mod(LOGO_VERTEX_SHADER)?;
         let logo_fs_module = backend.load_shader_mod(LOGO_FRAGMENT_SHADER)?;
 
-        let screen_size_buffer = backend.device.create_buffer_with_data(
-            bytemuck::cast_slice(&[backend.sc_desc.width, backend.sc_desc.height]),
+        let logo_size = 0;
+        let uniform_buffer = backend.device.create_buffer_with_data(
+            bytemuck::cast_slice(&[backend.sc_desc.width, backend.sc_desc.height, logo_size]),
             BufferUsage::UNIFORM | BufferUsage::COPY_DST,
         );
 
@@ -149,8 +155,8 @@ impl LogoRenderer {
                 Binding {
                     binding: 0,
                     resource: BindingResource::Buffer {
-                        buffer: &screen_size_buffer,
-                        range: 0..(2 * std::mem::size_of::<u32>()) as u64,
+                        buffer: &uniform_buffer,
+                        range: 0..UNIFORM_SIZE,
                     },
                 },
                 Binding {
@@ -223,24 +229,40 @@ impl LogoRenderer {
 
         Ok(Self {
             logo_render_pipeline,
-            screen_size_buffer,
+            uniform_buffer,
+            logo_size,
             logo_bindgroup,
             logo_texture,
         })
     }
 
+    pub fn declare_options(options: &mut Options) {
+        // The logo is hidden with a size of 0
+        options.declare("logo-size", Type::Int { min: 0, max: 4096 }, Value::Int(200)).expect("logo-size is declared once");
+    }
+
+    pub fn set_logo_size(&mut self, backend: &mut RenderBackend, logo_size: u32) {
+        self.logo_size = logo_size;
+        self.write_uniform(backend);
+    }
+
     pub fn resize(&mut self, backend: &mut RenderBackend) -> IOResult<()> {
-        let staging_screen_size_mapped = backend.device.create_buffer_mapped(
+        self.write_uniform(backend);
+        Ok(())
+    }
+
+    fn write_uniform(&self, backend: &mut RenderBackend) {
+        let staging_uniform_mapped = backend.device.create_buffer_mapped(
             &wgpu::BufferDescriptor {
-                label: Some("Staging screen size buffer"),
-                size: (2 * std::mem::size_of::<u32>()) as u64,
+                label: Some("Staging logo uniform buffer"),
+                size: UNIFORM_SIZE,
                 usage: BufferUsage::MAP_WRITE | BufferUsage::COPY_SRC | BufferUsage::STORAGE,
             }
         );
-        staging_screen_size_mapped.data.copy_from_slice(
-            bytemuck::cast_slice(&[backend.sc_desc.width, backend.sc_desc.height]),
+        staging_uniform_mapped.data.copy_from_slice(
+            bytemuck::cast_slice(&[backend.sc_desc.width, backend.sc_desc.height, self.logo_size]),
         );
-        let staging_screen_size_buffer = staging_screen_size_mapped.finish();
+        let staging_uniform_buffer = staging_uniform_mapped.finish();
 
         let mut stage_upload_encoder = backend.device.create_command_encoder(
             &wgpu::CommandEncoderDescriptor {
@@ -249,16 +271,14 @@ impl LogoRenderer {
         );
 
         stage_upload_encoder.copy_buffer_to_buffer(
-            &staging_screen_size_buffer,
+            &staging_uniform_buffer,
             0,
-            &self.screen_size_buffer,
+            &self.uniform_buffer,
             0,
-            (2 * std::mem::size_of::<u32>()) as u64,
+            UNIFORM_SIZE,
         );
 
         backend.queue.submit(&[stage_upload_encoder.finish()]);
-
-        Ok(())
     }
 
     pub async fn render(&mut self, backend: &mut RenderBackend, to_view: &wgpu::TextureView, _state: &State) -> IOResult<wgpu::CommandBuffer> {
@@ -292,7 +312,7 @@ impl LogoRenderer {
         Ok(encoder.finish())
     }
 
-    pub fn collect_textures<'a>(&'a self) -> Vec<&'a RichTexture> {
+    pub fn collect_textures(&self) -> Vec<&RichTexture> {
         vec![
             &self.logo_texture,
         ]
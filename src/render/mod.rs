@@ -20,6 +20,10 @@ use winit::{
 
 use crate::into_ioerror;
 use crate::state::State;
+use crate::layout::{LineLayout, FontConfig};
+use crate::options::{Changes, Options};
+use crate::viewport::Viewport;
+use crate::style;
 
 mod logo;
 use logo::LogoRenderer;
@@ -145,6 +149,8 @@ pub struct RenderState {
     backend: RenderBackend,
     logo_renderer: LogoRenderer,
     text_renderer: TextRenderer,
+    // The options which changed since they were last applied
+    option_changes: Changes,
 }
 
 impl RenderState {
@@ -158,6 +164,7 @@ impl RenderState {
             backend,
             logo_renderer,
             text_renderer,
+            option_changes: Changes::default(),
         })
     }
 
@@ -170,7 +177,55 @@ impl RenderState {
         Ok(())
     }
 
+    /// Sets the size of the viewport to the area the text is rendered in
+    pub fn set_viewport_size(&self, viewport: &mut Viewport) {
+        self.text_renderer.set_viewport_size(viewport);
+    }
+
+    /// Lays out lines the same way as the text renderer, for vertical motions in the editor
+    pub fn line_layout(&self) -> Box<dyn LineLayout> {
+        Box::new(self.text_renderer.line_layout())
+    }
+
+    /// The fonts which text is currently drawn with
+    pub fn font_config(&self) -> &FontConfig {
+        self.text_renderer.font_config()
+    }
+
+    pub fn set_font_config(&mut self, font_config: &FontConfig) -> IOResult<()> {
+        self.text_renderer.set_font_config(font_config)
+    }
+
+    /// Declares the options of the renderer, and subscribes to them so they're applied when rendering
+    pub fn subscribe_to_options(&mut self, options: &mut Options) {
+        LogoRenderer::declare_options(options);
+        TextRenderer::declare_options(options);
+        self.option_changes = options.subscribe();
+    }
+
+    fn apply_options(&mut self, options: &Options) -> IOResult<()> {
+        let changes = self.option_changes.take();
+        let changed = |name: &str| changes.iter().any(|changed| changed == name);
+
+        if changed("logo-size") {
+            self.logo_renderer.set_logo_size(&mut self.backend, options.int("logo-size") as u32);
+        }
+        if changed("atlas-page-size") || changed("glyph-margin") {
+            let page_size = options.int("atlas-page-size") as u32;
+            let glyph_margin = options.int("glyph-margin") as u32;
+            self.text_renderer.set_atlas_layout(&mut self.backend, page_size, glyph_margin)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the glyph atlas filled up since this was last called, so that some glyphs weren't drawn
+    pub fn take_atlas_filled(&mut self) -> bool {
+        self.text_renderer.take_atlas_filled()
+    }
+
     pub async fn render(&mut self, state: &State) -> IOResult<()> {
+        self.apply_options(&state.options)?;
+
         let current_texture_view = &self.backend.swap_chain.get_next_texture().map_err(|_| into_ioerror("Timeout"))?.view;
 
         let mut encoder = self.backend.device.create_command_encoder(
@@ -179,6 +234,7 @@ impl RenderState {
             }
         );
 
+        let background = state.faces.get("default").bg.unwrap_or(style::Color::rgb(0., 0., 0.));
         let clear_render_pass = encoder.begin_render_pass(
             &wgpu::RenderPassDescriptor {
                 color_attachments: &[
@@ -187,7 +243,12 @@ impl RenderState {
                         resolve_target: None,
                         load_op: wgpu::LoadOp::Clear,
                         store_op: wgpu::StoreOp::Store,
-                        clear_color: Color::BLACK,
+                        clear_color: Color {
+                            r: background.r as f64,
+                            g: background.g as f64,
+                            b: background.b as f64,
+                            a: background.a as f64,
+                        },
                     }
                 ],
                 depth_stencil_attachment: None,
@@ -0,0 +1,319 @@
+use std::io::Result as IOResult;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use wgpu::{
+    Buffer,
+    BufferUsage,
+    CommandEncoder,
+    TextureFormat,
+    Extent3d,
+};
+
+use rusttype::{
+    Font as RTFont,
+    Rect,
+};
+
+use crate::into_ioerror;
+use super::super::{RenderBackend, RichTexture};
+use super::packing::{ShelfPacker, PackedRect};
+
+// The page size and glyph margin can be changed with options, these are what they start as
+pub const DEFAULT_GLYPH_MARGIN: u32 = 1;
+pub const DEFAULT_PAGE_SIZE: u32 = 1024;
+
+const MAX_PAGES: usize = 4;
+
+// wgpu requires rows in buffer-to-texture copies to be aligned to this
+const COPY_BYTES_PER_ROW_ALIGNMENT: u32 = 256;
+
+/// Number of horizontal positions within a pixel each glyph can be rasterized at
+pub const SUBPIXEL_STEPS: u8 = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GlyphKey {
+    pub font: usize,
+    pub glyph_id: u32,
+    size_px_bits: u32,
+    subpixel: u8,
+}
+
+impl GlyphKey {
+    /// `x_frac` is the fractional pixel position of the pen, in 0..1
+    pub fn new(font: usize, glyph_id: u32, size_px: f32, x_frac: f32) -> Self {
+        let subpixel = (x_frac * SUBPIXEL_STEPS as f32).floor() as u8 % SUBPIXEL_STEPS;
+        Self {
+            font,
+            glyph_id,
+            size_px_bits: size_px.to_bits(),
+            subpixel,
+        }
+    }
+
+    pub fn size_px(&self) -> f32 {
+        f32::from_bits(self.size_px_bits)
+    }
+
+    pub fn subpixel_offset(&self) -> f32 {
+        self.subpixel as f32 / SUBPIXEL_STEPS as f32
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct AtlasEntry {
+    /// Index of the page holding the glyph
+    pub page: usize,
+    /// Area of the page holding the glyph, including the margin on all sides
+    pub canvas_rect: PackedRect,
+    /// Pixel bounding box of the glyph, relative to the pen position on the baseline
+    pub bounds: Rect<i32>,
+}
+
+struct ResidentGlyph {
+    // None for glyphs which have no pixels, i.e. spaces
+    entry: Option<AtlasEntry>,
+    last_used_frame: u64,
+}
+
+pub struct AtlasPage {
+    pub canvas: RichTexture,
+    packer: ShelfPacker,
+}
+
+/// Keeps rasterized glyphs resident in the glyph canvas pages across frames. Only
+/// glyphs not seen before are rasterized and uploaded.
+///
+/// Once all pages are full, the least recently used glyphs are evicted. Glyphs
+/// used in the current frame are never evicted, so quads created earlier in the
+/// frame stay valid and only the evicted glyphs need to be uploaded again once
+/// they come back into use.
+pub struct GlyphAtlas {
+    glyphs: HashMap<GlyphKey, ResidentGlyph>,
+    pages: Vec<AtlasPage>,
+    // Kept alive until the encoder copying from them has been submitted
+    staging_buffers: Vec<Buffer>,
+    page_size: u32,
+    // Empty pixels on each side of a glyph
+    glyph_margin: u32,
+
+    current_frame: u64,
+    /// Set when a glyph didn't fit even after evicting everything possible
+    pub overflowed: bool,
+}
+
+impl GlyphAtlas {
+    pub fn new(backend: &mut RenderBackend) -> IOResult<Self> {
+        let mut atlas = Self {
+            glyphs: HashMap::new(),
+            pages: Vec::new(),
+            staging_buffers: Vec::new(),
+            page_size: DEFAULT_PAGE_SIZE,
+            glyph_margin: DEFAULT_GLYPH_MARGIN,
+            current_frame: 0,
+            overflowed: false,
+        };
+        atlas.add_page(backend)?;
+
+        Ok(atlas)
+    }
+
+    pub fn pages(&self) -> &[AtlasPage] {
+        &self.pages
+    }
+
+    /// Changes the size of the pages and the margin around glyphs. All pages are
+    /// thrown away, so the bind groups of the old pages mustn't be used anymore.
+    pub fn set_layout(&mut self, backend: &mut RenderBackend, page_size: u32, glyph_margin: u32) -> IOResult<()> {
+        if (page_size, glyph_margin) == (self.page_size, self.glyph_margin) {
+            return Ok(());
+        }
+        self.page_size = page_size;
+        self.glyph_margin = glyph_margin;
+        self.glyphs.clear();
+        self.pages.clear();
+        self.add_page(backend)?;
+        Ok(())
+    }
+
+    pub fn uv_rect(&self, entry: &AtlasEntry) -> ([f32; 2], [f32; 2]) {
+        let size = self.page_size as f32;
+        let u_0 = (entry.canvas_rect.x + self.glyph_margin) as f32 / size;
+        let v_0 = (entry.canvas_rect.y + self.glyph_margin) as f32 / size;
+        (
+            [u_0, v_0],
+            [u_0 + entry.bounds.width() as f32 / size, v_0 + entry.bounds.height() as f32 / size],
+        )
+    }
+
+    fn add_page(&mut self, backend: &mut RenderBackend) -> IOResult<usize> {
+        let canvas = RichTexture::new(
+            backend,
+            TextureFormat::Rgba8UnormSrgb,
+            Extent3d {
+                width: self.page_size,
+                height: self.page_size,
+                depth: 1,
+            },
+            Some(&format!("Glyph canvas {}", self.pages.len())),
+        )?;
+
+        self.pages.push(AtlasPage {
+            canvas,
+            packer: ShelfPacker::new(self.page_size, self.page_size),
+        });
+
+        Ok(self.pages.len() - 1)
+    }
+
+    /// Forgets all glyphs, which is needed when the fonts change since glyph keys
+    /// refer to fonts by their place in the font chain
+    pub fn clear(&mut self) {
+        self.glyphs.clear();
+        for page in &mut self.pages {
+            page.packer.clear();
+        }
+    }
+
+    /// Should be called before any glyphs for a new frame are looked up
+    pub fn begin_frame(&mut self) {
+        self.current_frame += 1;
+    }
+
+    /// Should be called once the encoder passed to get_or_insert has been submitted
+    pub fn uploads_submitted(&mut self) {
+        self.staging_buffers.clear();
+    }
+
+    fn allocate(&mut self, backend: &mut RenderBackend, width: u32, height: u32) -> IOResult<Option<(usize, PackedRect)>> {
+        for (page_idx, page) in self.pages.iter_mut().enumerate() {
+            if let Some(rect) = page.packer.allocate(width, height) {
+                return Ok(Some((page_idx, rect)));
+            }
+        }
+
+        if self.pages.len() < MAX_PAGES {
+            let page_idx = self.add_page(backend)?;
+            return Ok(self.pages[page_idx].packer.allocate(width, height).map(|rect| (page_idx, rect)));
+        }
+
+        // Evict glyphs, oldest first, until there's room
+        let current_frame = self.current_frame;
+        let mut evictable: Vec<(u64, GlyphKey)> = self.glyphs
+            .iter()
+            .filter(|(_, glyph)| glyph.entry.is_some() && glyph.last_used_frame < current_frame)
+            .map(|(&key, glyph)| (glyph.last_used_frame, key))
+            .collect();
+        evictable.sort_by_key(|&(last_used_frame, _)| last_used_frame);
+
+        for (_, key) in evictable {
+            let entry = if let Some(ResidentGlyph { entry: Some(entry), .. }) = self.glyphs.remove(&key) {
+                entry
+            } else {
+                continue;
+            };
+
+            let page = &mut self.pages[entry.page];
+            page.packer.free(entry.canvas_rect);
+            if let Some(rect) = page.packer.allocate(width, height) {
+                return Ok(Some((entry.page, rect)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the atlas entry for the glyph, rasterizing it and recording an upload
+    /// into its page if it isn't resident yet. Returns None if the glyph has no
+    /// pixels or if it doesn't fit in any page.
+    pub fn get_or_insert(
+        &mut self,
+        backend: &mut RenderBackend,
+        encoder: &mut CommandEncoder,
+        font: &RTFont<'_>,
+        key: GlyphKey,
+    ) -> IOResult<Option<AtlasEntry>> {
+        if let Some(glyph) = self.glyphs.get_mut(&key) {
+            glyph.last_used_frame = self.current_frame;
+            return Ok(glyph.entry);
+        }
+
+        let glyph = font.glyph(rusttype::GlyphId(key.glyph_id.try_into().map_err(into_ioerror)?));
+        let glyph = glyph.scaled(rusttype::Scale::uniform(key.size_px()));
+        let glyph = glyph.positioned(rusttype::Point { x: key.subpixel_offset(), y: 0. });
+        let bounds = if let Some(pbb) = glyph.pixel_bounding_box() {
+            pbb
+        } else {
+            self.glyphs.insert(key, ResidentGlyph { entry: None, last_used_frame: self.current_frame });
+            return Ok(None);
+        };
+
+        let margin = self.glyph_margin;
+        let width = bounds.width() as u32 + 2 * margin;
+        let height = bounds.height() as u32 + 2 * margin;
+
+        let (page, canvas_rect) = if let Some(allocation) = self.allocate(backend, width, height)? {
+            allocation
+        } else {
+            self.overflowed = true;
+            return Ok(None);
+        };
+
+        // The margin is cleared so that linear sampling doesn't bleed in
+        // neighbouring glyphs, or leftovers from evicted ones
+        let bytes_per_row = align_to(width * 4, COPY_BYTES_PER_ROW_ALIGNMENT);
+        let mut data = vec![0u8; (bytes_per_row * height) as usize];
+
+        glyph.draw(|x, y, v| {
+            let i = (4 * (x + margin) + (y + margin) * bytes_per_row) as usize;
+            data[i] = 255;
+            data[i + 1] = 255;
+            data[i + 2] = 255;
+            data[i + 3] = (v * 255.) as u8;
+        });
+
+        let staging_buffer = backend.device.create_buffer_with_data(
+            &data,
+            BufferUsage::COPY_SRC,
+        );
+
+        encoder.copy_buffer_to_texture(
+            wgpu::BufferCopyView {
+                buffer: &staging_buffer,
+                offset: 0,
+                bytes_per_row,
+                rows_per_image: height,
+            },
+            wgpu::TextureCopyView {
+                texture: &self.pages[page].canvas.content,
+                mip_level: 0,
+                array_layer: 0,
+                origin: wgpu::Origin3d {
+                    x: canvas_rect.x,
+                    y: canvas_rect.y,
+                    z: 0,
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+        );
+
+        self.staging_buffers.push(staging_buffer);
+
+        let entry = AtlasEntry {
+            page,
+            canvas_rect,
+            bounds,
+        };
+        self.glyphs.insert(key, ResidentGlyph { entry: Some(entry), last_used_frame: self.current_frame });
+
+        Ok(Some(entry))
+    }
+}
+
+fn align_to(x: u32, alignment: u32) -> u32 {
+    x.div_ceil(alignment) * alignment
+}
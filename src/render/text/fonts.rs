@@ -0,0 +1,297 @@
+// Fonts are tried in order for every char, so that chars missing from the main
+// font, like sitelen pona in the private use area, are drawn with a fallback
+// font. Text is split into runs of chars using the same font, and each run is
+// shaped on its own.
+//
+// Fonts are given by name or by path. Names are looked up among the builtin
+// fonts first, and then among the font files installed in the usual places.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::Result as IOResult;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use harfbuzz_rs::{
+    Font as HBFont,
+    Owned,
+    UnicodeBuffer,
+    Feature,
+    Tag,
+};
+
+use rusttype::Font as RTFont;
+
+use crate::into_ioerror;
+use crate::layout::{Shaping, FontConfig};
+
+const BUILTIN_FONTS: &[(&str, &[u8])] = &[
+    ("Fira Code", include_bytes!("../../../resources/firacode-regular.ttf")),
+    ("linja pona", include_bytes!("../../../resources/linja-pona-4.1.otf")),
+];
+const SITELEN_PONA_FONT: &str = "linja pona";
+
+// Where installed fonts are looked for. ~ is the home directory.
+const FONT_DIRS: &[&str] = &[
+    "~/.local/share/fonts",
+    "~/.fonts",
+    "/usr/local/share/fonts",
+    "/usr/share/fonts",
+    "~/Library/Fonts",
+    "/Library/Fonts",
+    "/System/Library/Fonts",
+];
+
+// linja pona turns words into sitelen pona with these
+const SITELEN_PONA_FEATURES: &[[char; 4]] = &[
+    ['l', 'i', 'g', 'a'],
+    ['c', 'l', 'i', 'g'],
+    ['c', 'a', 'l', 't'],
+    ['r', 'l', 'i', 'g'],
+];
+
+pub struct Font {
+    hb_font: Owned<HBFont<'static>>,
+    rt_font: RTFont<'static>,
+}
+
+impl Font {
+    fn from_static(name: &str, data: &'static [u8]) -> IOResult<Self> {
+        let rt_font = RTFont::try_from_bytes(data)
+            .ok_or_else(|| into_ioerror(format!("Invalid font data in {}", name)))?;
+        let hb_face = harfbuzz_rs::Face::from_bytes(data, 0);
+
+        Ok(Self {
+            hb_font: HBFont::new(hb_face),
+            rt_font,
+        })
+    }
+
+    // Both fonts own a copy of the data of a font file, since rusttype and
+    // harfbuzz can't share an owned buffer
+    fn from_vec(name: &str, data: Vec<u8>) -> IOResult<Self> {
+        let rt_font = RTFont::try_from_vec(data.clone())
+            .ok_or_else(|| into_ioerror(format!("Invalid font data in {}", name)))?;
+        let hb_blob = harfbuzz_rs::Blob::with_bytes_owned(data, |data: &Vec<u8>| data.as_slice());
+        let hb_face = harfbuzz_rs::Face::new(hb_blob, 0);
+
+        Ok(Self {
+            hb_font: HBFont::new(hb_face),
+            rt_font,
+        })
+    }
+
+    /// The font to rasterize glyphs with
+    pub fn rt_font(&self) -> &RTFont<'_> {
+        &self.rt_font
+    }
+
+    /// A builtin font, an installed font with the name, or a font file
+    pub fn load(name: &str) -> IOResult<Self> {
+        let simplified = simplify_font_name(name);
+        if let Some(&(builtin, data)) = BUILTIN_FONTS.iter().find(|(builtin, _)| simplify_font_name(builtin) == simplified) {
+            return Font::from_static(builtin, data);
+        }
+
+        let path = if Path::new(name).is_file() {
+            PathBuf::from(name)
+        } else {
+            find_installed(name).ok_or_else(|| into_ioerror(format!("No font named {}", name)))?
+        };
+        let data = fs::read(&path).map_err(|e| into_ioerror(format!("Couldn't read {}: {}", path.display(), e)))?;
+        Font::from_vec(&path.display().to_string(), data)
+    }
+
+    fn has_glyph(&self, ch: char) -> bool {
+        self.rt_font.glyph(ch).id().0 != 0
+    }
+}
+
+/// A shaped glyph, with its position in pixels
+pub struct Glyph {
+    /// The bytes of the text in the cluster of the glyph
+    pub byte_span: Range<usize>,
+    /// Index of the font in the chain
+    pub font: usize,
+    pub glyph_id: u32,
+    pub advance: [f32; 2],
+    pub offset: [f32; 2],
+}
+
+pub struct FontChain {
+    // The first font is the main one, the others are fallbacks in order
+    fonts: Vec<Font>,
+    // The font used first when shaping sitelen pona
+    sitelen_pona_font: usize,
+}
+
+impl FontChain {
+    /// The fonts of the config, followed by linja pona if they don't include it
+    /// so that sitelen pona can always be shown
+    pub fn load(config: &FontConfig) -> IOResult<Self> {
+        let mut names = config.fonts.clone();
+        let is_sitelen_pona = |name: &String| simplify_font_name(name) == simplify_font_name(SITELEN_PONA_FONT);
+        let sitelen_pona_font = match names.iter().position(is_sitelen_pona) {
+            Some(font_idx) => font_idx,
+            None => {
+                names.push(SITELEN_PONA_FONT.to_string());
+                names.len() - 1
+            }
+        };
+
+        let fonts = names
+            .iter()
+            .map(|name| Font::load(name))
+            .collect::<IOResult<Vec<_>>>()?;
+        Ok(Self { fonts, sitelen_pona_font })
+    }
+
+    pub fn primary(&self) -> &Font {
+        &self.fonts[0]
+    }
+
+    pub fn get(&self, font_idx: usize) -> &Font {
+        &self.fonts[font_idx]
+    }
+
+    /// Shapes text into glyphs at a font size, using the first font of the chain
+    /// which has each char. Sitelen pona is shaped with its font first, with its
+    /// ligatures. The glyphs still keep the bytes of the words they replace.
+    pub fn shape(&self, text: &str, size_px: f32, shaping: Shaping) -> Vec<Glyph> {
+        let mut order: Vec<usize> = (0..self.fonts.len()).collect();
+        let mut features = Vec::new();
+        if shaping == Shaping::SitelenPona {
+            order.retain(|&font_idx| font_idx != self.sitelen_pona_font);
+            order.insert(0, self.sitelen_pona_font);
+            features = SITELEN_PONA_FEATURES
+                .iter()
+                .map(|&[a, b, c, d]| Feature::new(Tag::new(a, b, c, d), 1, ..))
+                .collect();
+        }
+
+        let runs = split_runs(text, &order, |font_idx, ch| self.fonts[font_idx].has_glyph(ch));
+
+        let mut glyphs = Vec::with_capacity(text.len());
+        for (run, font_idx) in runs {
+            let hb_font = &self.fonts[font_idx].hb_font;
+            // h = harfbuzz, p = pixels
+            let h2p = size_px / hb_font.scale().1 as f32;
+
+            let unicode_buffer = UnicodeBuffer::new().add_str(&text[run.clone()]);
+            let glyph_buffer = harfbuzz_rs::shape(hb_font, unicode_buffer, &features);
+            let infos = glyph_buffer.get_glyph_infos();
+            let positions = glyph_buffer.get_glyph_positions();
+
+            for (i, (info, position)) in infos.iter().zip(positions).enumerate() {
+                let start = run.start + info.cluster as usize;
+                let end = infos.get(i + 1).map(|next| run.start + next.cluster as usize).unwrap_or(run.end);
+                glyphs.push(Glyph {
+                    byte_span: start..end,
+                    font: font_idx,
+                    glyph_id: info.codepoint,
+                    advance: [position.x_advance as f32 * h2p, position.y_advance as f32 * h2p],
+                    offset: [position.x_offset as f32 * h2p, position.y_offset as f32 * h2p],
+                });
+            }
+        }
+        glyphs
+    }
+}
+
+// Makes font names and file names comparable, e.g. "DejaVu Sans Mono" and DejaVuSansMono.ttf
+fn simplify_font_name(name: &str) -> String {
+    name.chars().filter(|ch| ch.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+// Searches the font directories for a font file named after the font
+fn find_installed(name: &str) -> Option<PathBuf> {
+    let wanted = simplify_font_name(name);
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let mut dirs: Vec<PathBuf> = FONT_DIRS
+        .iter()
+        .filter_map(|&dir| match dir.strip_prefix("~/") {
+            Some(in_home) => home.as_ref().map(|home| home.join(in_home)),
+            None => Some(PathBuf::from(dir)),
+        })
+        .collect();
+
+    // Directories can be reached more than once through symlinks, which can also form cycles
+    let mut visited = HashSet::new();
+    while let Some(dir) = dirs.pop() {
+        let canonical = match fs::canonicalize(&dir) {
+            Ok(canonical) => canonical,
+            Err(_) => continue,
+        };
+        if !visited.insert(canonical) {
+            continue;
+        }
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+
+            let is_font = matches!(path.extension().and_then(|ext| ext.to_str()), Some("ttf") | Some("otf"));
+            let stem = path.file_stem().map(|stem| simplify_font_name(&stem.to_string_lossy())).unwrap_or_default();
+            if is_font && (stem == wanted || stem == format!("{}regular", wanted)) {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+// Chars which are drawn with the font of the char before them, if it has them, so that
+// they don't split runs. Joiners and combining marks only work inside a run.
+fn continues_run(ch: char) -> bool {
+    ch.is_whitespace() || matches!(
+        ch,
+        '\u{300}'..='\u{36f}' | '\u{200c}'..='\u{200d}' | '\u{20d0}'..='\u{20ff}' | '\u{fe00}'..='\u{fe0f}'
+    )
+}
+
+// Splits text into runs of the same font, trying the fonts in order. Chars which
+// no font has are put in the first font, which draws them as missing glyphs.
+fn split_runs(text: &str, order: &[usize], has_glyph: impl Fn(usize, char) -> bool) -> Vec<(Range<usize>, usize)> {
+    let mut runs: Vec<(Range<usize>, usize)> = Vec::new();
+    for (byte, ch) in text.char_indices() {
+        let end = byte + ch.len_utf8();
+        let font = match runs.last() {
+            Some(&(_, last_font)) if continues_run(ch) && has_glyph(last_font, ch) => last_font,
+            _ => order.iter().copied().find(|&font_idx| has_glyph(font_idx, ch)).unwrap_or(order[0]),
+        };
+
+        match runs.last_mut() {
+            Some((run, last_font)) if *last_font == font => run.end = end,
+            _ => runs.push((byte..end, font)),
+        }
+    }
+    runs
+}
+
+#[test]
+fn test_split_runs() {
+    // Font 0 has ascii, font 1 has spaces and sitelen pona
+    let has_glyph = |font_idx: usize, ch: char| match ch {
+        ' ' => true,
+        'a'..='z' => font_idx == 0,
+        '\u{f1900}'..='\u{f19ff}' => font_idx == 1,
+        _ => false,
+    };
+
+    let text = "ni \u{f1900} \u{f1901} li";
+    let sp = '\u{f1900}'.len_utf8();
+    assert_eq!(split_runs(text, &[0, 1], has_glyph), vec![(0..3, 0), (3..3 + 2 * sp + 2, 1), (3 + 2 * sp + 2..text.len(), 0)]);
+
+    assert_eq!(split_runs("a\u{1f600}", &[0, 1], has_glyph), vec![(0..5, 0)]);
+    assert_eq!(split_runs("", &[0, 1], has_glyph), vec![]);
+
+    // Chars which several fonts have go in the first of them in the order
+    assert_eq!(split_runs(" ni", &[0, 1], has_glyph), vec![(0..3, 0)]);
+    assert_eq!(split_runs(" ni", &[1, 0], has_glyph), vec![(0..1, 1), (1..3, 0)]);
+}
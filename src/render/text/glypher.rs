@@ -1,265 +1,519 @@
 use std::io::Result as IOResult;
-use std::convert::TryInto;
-
-use wgpu::{
-    Buffer,
-    BufferUsage,
-};
-
-use harfbuzz_rs::{
-    Font as HBFont,
-    Owned,
-    GlyphPosition,
-};
-
-use rusttype::{
-    Font as RTFont,
-};
-
-use crate::into_ioerror;
-use crate::state::State;
-use super::super::{RenderBackend, RichTexture};
-use super::text_gpu_primitives::Vertex;
-
-const GLYPH_MARGIN: usize = 1;
-
-const FONT_SIZE_PX: f32 = 24.0; // For UV-rendering
-const FONT_DATA: &[u8] = include_bytes!("../../../resources/firacode-regular.ttf");
-
-struct Glyph<'a> {
-    from_ref: &'a str,
-    byte_span: std::ops::Range<usize>,
-
-    position: GlyphPosition,
-    glyph_id: u32,
+use std::ops::Range;
+use std::rc::Rc;
+
+use rusttype::Scale;
+
+use crate::state::{State, Mode};
+use crate::layout::{LineLayout, Shaping, FontConfig};
+use crate::viewport::Viewport;
+use crate::style::{Face, Color};
+use super::super::RenderBackend;
+use super::text_gpu_primitives::{Vertex, QuadVertex, GrowableVertexBuffer};
+use super::atlas::{GlyphAtlas, GlyphKey, AtlasPage};
+use super::fonts::{FontChain, Glyph};
+
+
+// For text whose face has no foreground color, which only happens if the theme unsets it
+const FALLBACK_FG: Color = Color::rgb(1., 1., 1.);
+const CURSOR_BAR_WIDTH_PX: f32 = 2.;
+
+// Placement of lines under and through text, in pixels from the baseline
+const DECORATION_THICKNESS_PX: f32 = 1.;
+const UNDERLINE_OFFSET_PX: f32 = 3.;
+// Relative to the font size
+const STRIKETHROUGH_OFFSET: f32 = -0.3;
+
+/// Lays out lines the same way as the glypher, without needing the renderer
+pub struct HBLineLayout {
+    fonts: Rc<FontChain>,
+    size_px: f32,
 }
 
-impl <'a> Glyph<'a> {
-    // TODO: Maybe pass a unicode buffer? Kinda cessary though...
-    fn create_glyph_iter(text: &'a str, font: &HBFont) -> Vec<Glyph<'a>> {
-        let unicode_buffer = harfbuzz_rs::UnicodeBuffer::new().add_str(text);
-        let glyph_buffer = harfbuzz_rs::shape(font, unicode_buffer, &[]);
-
-        let infos = glyph_buffer.get_glyph_infos();
-        let mut spans = Vec::with_capacity(infos.len());
-
-        for i in 0..infos.len() {
-            let start = infos[i].cluster as usize;
-            let next = infos.get(i+1).map(|x| x.cluster as usize).unwrap_or(text.len());
-            spans.push(start..next);
+impl LineLayout for HBLineLayout {
+    fn cluster_positions(&self, line: &str, shaping: Shaping) -> Vec<(usize, f32)> {
+        let mut positions: Vec<(usize, f32)> = Vec::new();
+        let mut x = 0.;
+        for glyph in self.fonts.shape(line, self.size_px, shaping) {
+            // Glyphs after the first in a cluster don't start a new position
+            if positions.last().map(|&(start, _)| start != glyph.byte_span.start).unwrap_or(true) {
+                positions.push((glyph.byte_span.start, x));
+            }
+            x += glyph.advance[0];
         }
-
-        let positions = glyph_buffer.get_glyph_positions();
+        positions.push((line.len(), x));
         positions
-            .iter()
-            .enumerate()
-            .map(|(i, &pos)| Glyph {
-                from_ref: text,
-                byte_span: spans[i].clone(),
-                position: pos,
-                glyph_id: infos[i].codepoint,
-            })
-            .collect()
-    }
-
-    fn get_content(&'a self) -> &'a str {
-        &self.from_ref[self.byte_span.clone()]
     }
 }
 
 pub struct Glypher {
-    hb_font: Owned<HBFont<'static>>,
-    rt_font: RTFont<'static>, // TODO: Change this to support dynamic fonts
-    text_rendered_cache: String,
+    fonts: Rc<FontChain>,
+    // The config the fonts were loaded from
+    font_config: FontConfig,
+    // Size of the text with the zoom
+    size_px: f32,
+    atlas: GlyphAtlas,
+    // Whether the atlas was full in the last frame, so that it's only reported when it fills up
+    atlas_was_full: bool,
     window_size: (f32, f32),
+    // Distance from the top of a line to the baseline
+    ascent: f32,
+    // Verticies for the text being uploaded, split by which atlas page the glyphs are in
+    page_verticies: Vec<Vec<Vertex>>,
+    // Quads drawn behind the text, i.e. backgrounds, selections and cursors
+    background_verticies: Vec<QuadVertex>,
+    // Quads drawn on top of the text, i.e. underlines and strikethroughs
+    decoration_verticies: Vec<QuadVertex>,
 }
 
-impl Glypher {
-    pub fn new() -> IOResult<Self> {
-        let rt_font = RTFont::try_from_bytes(FONT_DATA).ok_or(into_ioerror("Invalid font data!"))?;
+/// What was written to the vertex buffers by Glypher::upload
+pub(super) struct UploadedText {
+    /// The range of glyph verticies using each atlas page
+    pub page_ranges: Vec<Range<u32>>,
+    /// Quad verticies to draw before the glyphs
+    pub background_range: Range<u32>,
+    /// Quad verticies to draw after the glyphs
+    pub decoration_range: Range<u32>,
+    /// Whether the atlas filled up in this frame, so that some glyphs weren't drawn
+    pub atlas_filled: bool,
+}
 
-        let hb_face = harfbuzz_rs::Face::from_bytes(FONT_DATA, 0);
-        let hb_font = HBFont::new(hb_face);
+impl Glypher {
+    pub fn new(backend: &mut RenderBackend) -> IOResult<Self> {
+        let font_config = FontConfig::default();
+        let fonts = FontChain::load(&font_config)?;
 
         Ok(Self {
-            hb_font,
-            rt_font,
-            text_rendered_cache: "".to_string(),
+            atlas: GlyphAtlas::new(backend)?,
+            atlas_was_full: false,
             window_size: (1., 1.),
+            ascent: fonts.primary().rt_font().v_metrics(Scale::uniform(font_config.zoomed_size_px())).ascent,
+            size_px: font_config.zoomed_size_px(),
+            fonts: Rc::new(fonts),
+            font_config,
+            page_verticies: Vec::new(),
+            background_verticies: Vec::new(),
+            decoration_verticies: Vec::new(),
         })
     }
 
     pub(super) fn resize(&mut self, backend: &mut RenderBackend) -> IOResult<()> {
-        self.text_rendered_cache = "".to_string();
         self.window_size = (backend.sc_desc.width as f32, backend.sc_desc.height as f32);
 
         Ok(())
     }
 
-    pub(super) async fn upload(
-        &mut self,
-        backend: &mut RenderBackend,
-        state: &State,
-        glyph_vertex_buffer: &mut Buffer, // Let's just assume everything fits :)
-        glyph_canvas: &mut RichTexture,
-    ) -> IOResult<Option<u32>> {
-        // if state.content == self.text_rendered_cache {
-        //     return Ok(None);
-        // }
+    // Where the status line starts. The lines above it are the viewport, which
+    // only has whole lines, and the status line gets the pixels left below.
+    fn status_top(&self) -> f32 {
+        let viewport_lines = ((self.window_size.1 / self.size_px).floor() - 1.).max(0.);
+        viewport_lines * self.size_px
+    }
 
-        let mut encoder = backend.device.create_command_encoder(
-            &wgpu::CommandEncoderDescriptor {
-                label: Some("Texture upload encoder"),
-            }
-        );
+    pub(super) fn set_viewport_size(&self, viewport: &mut Viewport) {
+        viewport.set_size(self.window_size.0, self.status_top(), self.size_px);
+    }
 
-        let canvas_buf_mapped = backend.device.create_buffer_mapped(
-            &wgpu::BufferDescriptor {
-                label: Some("Canvas staging buffer"),
-                size: (glyph_canvas.extent.width * glyph_canvas.extent.height * 4) as u64,
-                usage: BufferUsage::COPY_SRC,
-            },
-        );
+    pub(super) fn line_layout(&self) -> HBLineLayout {
+        HBLineLayout {
+            fonts: Rc::clone(&self.fonts),
+            size_px: self.size_px,
+        }
+    }
 
-        // Render text
-        let mut verticies: Vec<Vertex> = Vec::new();
+    pub(super) fn font_config(&self) -> &FontConfig {
+        &self.font_config
+    }
+
+    /// Loads the fonts of the config, unless only the size changed. The glyphs in
+    /// the atlas are thrown away, since they were rasterized from the old fonts or
+    /// at the old size.
+    pub(super) fn set_font_config(&mut self, font_config: &FontConfig) -> IOResult<()> {
+        if font_config.fonts != self.font_config.fonts {
+            self.fonts = Rc::new(FontChain::load(font_config)?);
+        }
+        self.size_px = font_config.zoomed_size_px();
+        self.ascent = self.fonts.primary().rt_font().v_metrics(Scale::uniform(self.size_px)).ascent;
+        self.font_config = font_config.clone();
+        self.atlas.clear();
+        Ok(())
+    }
 
-        // h = harfbuzz, u = unit position for gpu
-        let h2u_x = FONT_SIZE_PX * 2. / (self.hb_font.scale().1 as f32 * self.window_size.0);
-        let h2u_y = FONT_SIZE_PX * 2. / (self.hb_font.scale().1 as f32 * self.window_size.1);
+    pub(super) fn set_atlas_layout(&mut self, backend: &mut RenderBackend, page_size: u32, glyph_margin: u32) -> IOResult<()> {
+        self.atlas.set_layout(backend, page_size, glyph_margin)
+    }
 
-        let glyphs = Glyph::create_glyph_iter(&state.content, &self.hb_font);
+    pub(super) fn atlas_pages(&self) -> &[AtlasPage] {
+        self.atlas.pages()
+    }
 
-        let mut current_xy_position: [f32; 2] = [0., 0., ];
-        let mut current_u: usize = 0;
-        let mut current_v: usize = 0;
+    // Width of a piece of text in pixels
+    fn text_width(&self, text: &str) -> f32 {
+        self.fonts
+            .shape(text, self.size_px, Shaping::Plain)
+            .iter()
+            .map(|glyph| glyph.advance[0])
+            .sum()
+    }
 
-        for glyph_info in glyphs {
-            let gl_pos = glyph_info.position;
-            let in_selection = state.cursor_range.contains(&glyph_info.byte_span.start);
+    // Converts from pixels from the top left corner of the window to unit positions for the gpu
+    fn to_unit(&self, pixel_pos: [f32; 2]) -> [f32; 2] {
+        [
+            pixel_pos[0] * 2. / self.window_size.0 - 1.,
+            1. - pixel_pos[1] * 2. / self.window_size.1,
+        ]
+    }
 
-            // Special case for newline
-            // TODO: I'm not 100% sure how fonts handle newlines. For top-to-bottom fonts, should we step right?
-            // Look this up and make a proper solution.
+    // A solid rectangle, given in pixels. The rectangle is cut to clip_x, and None if nothing is left.
+    fn solid_quad(&self, x: Range<f32>, y: Range<f32>, clip_x: &Range<f32>, color: Color) -> Option<[QuadVertex; 6]> {
+        let x = x.start.max(clip_x.start)..x.end.min(clip_x.end);
+        if x.start >= x.end {
+            return None;
+        }
 
-            if glyph_info.get_content() == "\n" {
-                current_xy_position[0] = 0.;
-                current_xy_position[1] += -self.hb_font.scale().1 as f32 * h2u_y; // negative = down
-                continue;
+        Some(QuadVertex::create_quad(self.to_unit([x.start, y.start]), self.to_unit([x.end, y.end]), color.as_array()))
+    }
+
+    // Adds a quad behind the text in the background color of the face, if it has one
+    fn emit_background(&mut self, x: Range<f32>, y: Range<f32>, clip_x: &Range<f32>, face: Face) {
+        if let Some(quad) = face.bg.and_then(|bg| self.solid_quad(x, y, clip_x, bg)) {
+            self.background_verticies.extend(&quad);
+        }
+    }
+
+    // Adds the underline and strikethrough of the face, in its foreground color
+    fn emit_decorations(&mut self, x: Range<f32>, baseline: f32, clip_x: &Range<f32>, face: Face) {
+        let color = face.fg.unwrap_or(FALLBACK_FG);
+        let mut offsets = Vec::new();
+        if face.underline {
+            offsets.push(UNDERLINE_OFFSET_PX);
+        }
+        if face.strikethrough {
+            offsets.push(STRIKETHROUGH_OFFSET * self.size_px);
+        }
+
+        for offset in offsets {
+            let y = baseline + offset;
+            if let Some(quad) = self.solid_quad(x.clone(), y..y + DECORATION_THICKNESS_PX, clip_x, color) {
+                self.decoration_verticies.extend(&quad);
+            }
+        }
+    }
+
+    // Adds a quad for each glyph of a shaped text, sorted by atlas page, together with the
+    // backgrounds and decorations of their faces. top_left is where the line of text starts, in
+    // pixels from the top left corner of the window with y growing downwards. Glyphs are cut to
+    // clip_x. face_at is called with the byte offset of each glyph in the text.
+    //
+    // Returns the x position of the start of every cluster, like LineLayout::cluster_positions.
+    fn emit_text(
+        &mut self,
+        backend: &mut RenderBackend,
+        encoder: &mut wgpu::CommandEncoder,
+        glyphs: Vec<Glyph>,
+        top_left: [f32; 2],
+        clip_x: Range<f32>,
+        face_at: impl Fn(usize) -> Face,
+    ) -> IOResult<Vec<(usize, f32)>> {
+        let size_px = self.size_px;
+        let line_y = top_left[1]..top_left[1] + size_px;
+        let baseline = top_left[1] + self.ascent;
+        let mut pen_position = [top_left[0], baseline];
+
+        // The last cluster ends at the end of the text
+        let text_len = glyphs.iter().map(|glyph| glyph.byte_span.end).max().unwrap_or(0);
+        let mut cluster_positions: Vec<(usize, f32)> = Vec::with_capacity(glyphs.len() + 1);
+
+        for glyph_info in glyphs {
+            let face = face_at(glyph_info.byte_span.start);
+
+            if cluster_positions.last().map(|&(start, _)| start != glyph_info.byte_span.start).unwrap_or(true) {
+                cluster_positions.push((glyph_info.byte_span.start, pen_position[0]));
             }
 
             let render_pos = [
-                current_xy_position[0] + gl_pos.x_offset as f32 * h2u_x,
-                current_xy_position[1] + gl_pos.y_offset as f32 * h2u_y,
+                pen_position[0] + glyph_info.offset[0],
+                pen_position[1] - glyph_info.offset[1],
             ];
-            current_xy_position[0] += gl_pos.x_advance as f32 * h2u_x;
-            current_xy_position[1] += gl_pos.y_advance as f32 * h2u_y;
+            let advance_x = pen_position[0]..pen_position[0] + glyph_info.advance[0];
+            pen_position[0] += glyph_info.advance[0];
+            pen_position[1] -= glyph_info.advance[1];
 
-            let ext = if let Some(ext) = self.hb_font.get_glyph_extents(glyph_info.glyph_id) {
-                ext
-            } else {
+            self.emit_background(advance_x.clone(), line_y.clone(), &clip_x, face);
+            self.emit_decorations(advance_x, baseline, &clip_x, face);
+
+            if render_pos[0] >= clip_x.end {
                 continue;
-            };
+            }
 
+            // The glyph is rasterized at the fractional part of the position, so the quad can be snapped to whole pixels
+            let pixel_pos = [render_pos[0].floor(), render_pos[1].round()];
+            let key = GlyphKey::new(glyph_info.font, glyph_info.glyph_id, size_px, render_pos[0] - pixel_pos[0]);
 
-            let glyph = self.rt_font.glyph(rusttype::GlyphId(glyph_info.glyph_id.try_into().map_err(into_ioerror)?));
-            let glyph = glyph.scaled(rusttype::Scale::uniform(FONT_SIZE_PX));
-            let glyph = glyph.positioned(rusttype::Point { x: current_u as f32, y: current_v as f32 });
-            let bounds = if let Some(pbb) = glyph.pixel_bounding_box() {
-                pbb
+            let entry = if let Some(entry) = self.atlas.get_or_insert(backend, encoder, self.fonts.get(glyph_info.font).rt_font(), key)? {
+                entry
             } else {
                 continue;
             };
 
-            let current_u_frac = current_u as f32 / glyph_canvas.extent.width as f32;
-            let current_v_frac = current_v as f32 / glyph_canvas.extent.height as f32;
-            let u_width_frac = bounds.width() as f32 / glyph_canvas.extent.width as f32;
-            let v_height_frac = bounds.height() as f32 / glyph_canvas.extent.height as f32;
+            let (mut uv_0, mut uv_1) = self.atlas.uv_rect(&entry);
 
-            if current_u_frac + u_width_frac > 1. || current_v_frac + v_height_frac > 1. {
-                break;
+            // Glyphs partly outside of clip_x are cut, together with the part of the atlas they sample
+            let x = pixel_pos[0] + entry.bounds.min.x as f32..pixel_pos[0] + entry.bounds.max.x as f32;
+            let clipped_x = x.start.max(clip_x.start)..x.end.min(clip_x.end);
+            if clipped_x.start >= clipped_x.end {
+                continue;
             }
+            let u_per_px = (uv_1[0] - uv_0[0]) / (x.end - x.start);
+            uv_0[0] += (clipped_x.start - x.start) * u_per_px;
+            uv_1[0] -= (x.end - clipped_x.end) * u_per_px;
 
-            let x_bearing = ext.x_bearing as f32 * h2u_x;
-            let y_bearing = ext.y_bearing as f32 * h2u_y;
-            let ext_width = ext.width as f32 * h2u_x;
-            let ext_height = ext.height as f32 * h2u_y;
-
-            verticies.extend(
-                &Vertex::create_quad(
-                    [render_pos[0] + x_bearing, render_pos[1] + y_bearing],
-                    [render_pos[0] + x_bearing + ext_width, render_pos[1] + y_bearing + ext_height],
-                    [current_u_frac, current_v_frac],
-                    [current_u_frac + u_width_frac, current_v_frac + v_height_frac],
-                ),
+            if self.page_verticies.len() <= entry.page {
+                self.page_verticies.resize_with(entry.page + 1, Vec::new);
+            }
+            let quad = Vertex::create_quad(
+                self.to_unit([clipped_x.start, pixel_pos[1] + entry.bounds.min.y as f32]),
+                self.to_unit([clipped_x.end, pixel_pos[1] + entry.bounds.max.y as f32]),
+                uv_0,
+                uv_1,
+                face.fg.unwrap_or(FALLBACK_FG).as_array(),
             );
+            self.page_verticies[entry.page].extend(&quad);
+        }
+
+        cluster_positions.push((text_len, pen_position[0]));
+        Ok(cluster_positions)
+    }
 
-            let width = bounds.width() as usize + GLYPH_MARGIN;
-            let height = bounds.height() as usize + GLYPH_MARGIN;
-
-            // Clear the area + margin
-            for x in current_u..current_u+width {
-                for y in current_v..current_v+height {
-                    let i = 4 * (x + y * glyph_canvas.extent.width as usize);
-                    for c in 0..4 {
-                        canvas_buf_mapped.data[i + c] = 0;
-                    }
-                }
+    // Adds the backgrounds of the selections and the cursors on a line. Selections
+    // which include the newline at the end of the line reach past its end.
+    fn emit_selections(
+        &mut self,
+        state: &State,
+        line_idx: usize,
+        cluster_positions: &[(usize, f32)],
+        y: Range<f32>,
+        clip_x: &Range<f32>,
+    ) {
+        let line_range = state.content.line_range(line_idx);
+        let has_newline = line_idx + 1 < state.content.len_lines();
+        let eol_width = self.text_width(" ");
+
+        // The x position of a byte in the line, and the x position of the cluster after it
+        let x_of = |byte: usize| {
+            let idx = cluster_positions
+                .iter()
+                .rposition(|&(start, _)| start <= byte - line_range.start)
+                .unwrap_or(0);
+            let x = cluster_positions[idx].1;
+            let next_x = cluster_positions.get(idx + 1).map(|&(_, x)| x).unwrap_or(x + eol_width);
+            (x, next_x)
+        };
+
+        let primary = state.selections.primary();
+        for &sel in state.selections.iter() {
+            if sel.end() < line_range.start || sel.start() > line_range.end {
+                continue;
+            }
+            let is_primary = sel == primary;
+
+            let start = sel.start().max(line_range.start);
+            let end = sel.end().min(line_range.end);
+            let includes_newline = has_newline && sel.end() > line_range.end;
+            if start < end || includes_newline {
+                let x_end = if includes_newline { x_of(end).0 + eol_width } else { x_of(end).0 };
+                let face = state.faces.get(if is_primary { "primary-selection" } else { "selection" });
+                self.emit_background(x_of(start).0..x_end, y.clone(), clip_x, face);
             }
 
-            glyph.draw(|rx, ry, v| {
-                let x = rx + current_u as u32;
-                let y = ry + current_v as u32;
-
-                let i = 4 * (x + y * glyph_canvas.extent.width) as usize;
-                canvas_buf_mapped.data[i] = 255;
-                canvas_buf_mapped.data[i + 1] = 255;
-                canvas_buf_mapped.data[i + 2] = 255;
-                if in_selection {
-                    canvas_buf_mapped.data[i] = 0;
-                }
-                canvas_buf_mapped.data[i + 3] = (v * 255.) as u8;
-            });
-
-            current_u += width;
+            if line_range.contains(&sel.cursor) || sel.cursor == line_range.end {
+                let (x, next_x) = x_of(sel.cursor);
+                // A block over the character at the cursor, or a bar before it while inserting
+                let width = if state.mode == Mode::Insert { CURSOR_BAR_WIDTH_PX } else { next_x - x };
+                let face = state.faces.get(if is_primary { "primary-cursor" } else { "cursor" });
+                self.emit_background(x..x + width, y.clone(), clip_x, face);
+            }
         }
+    }
+
+    // Where the info popup is drawn, as x and y ranges in pixels. It's in the bottom
+    // right corner of the viewport, with half a line of space around the text.
+    fn info_box(&self, info: &str) -> (Range<f32>, Range<f32>) {
+        let padding = self.size_px / 2.;
+        let width = info.lines().map(|line| self.text_width(line)).fold(0., f32::max) + 2. * padding;
+        let height = info.lines().count() as f32 * self.size_px + 2. * padding;
 
-        let canvas_buf = canvas_buf_mapped.finish();
-
-        // Upload UV-canvas
-        encoder.copy_buffer_to_texture(
-            wgpu::BufferCopyView {
-                buffer: &canvas_buf,
-                offset: 0,
-                bytes_per_row: glyph_canvas.extent.width * 4,
-                rows_per_image: glyph_canvas.extent.height,
-            },
-            wgpu::TextureCopyView {
-                texture: &glyph_canvas.content,
-                mip_level: 0,
-                array_layer: 0,
-                origin: Default::default(),
-            },
-            glyph_canvas.extent,
+        let right = self.window_size.0 - padding;
+        let bottom = self.status_top();
+        ((right - width).max(0.)..right, (bottom - height).max(0.)..bottom)
+    }
+
+    // Adds the info popup of the state. The text of the viewport is clipped so that it isn't drawn over it.
+    fn emit_info(
+        &mut self,
+        backend: &mut RenderBackend,
+        encoder: &mut wgpu::CommandEncoder,
+        info: &str,
+        text_face: Face,
+        info_face: Face,
+    ) -> IOResult<()> {
+        let (x, y) = self.info_box(info);
+        let face = text_face.with(info_face);
+        self.emit_background(x.clone(), y.clone(), &x, face);
+
+        let padding = self.size_px / 2.;
+        let fg_only = Face { bg: None, ..face };
+        for (line_idx, line) in info.lines().enumerate() {
+            let top = y.start + padding + line_idx as f32 * self.size_px;
+            let glyphs = self.fonts.shape(line, self.size_px, Shaping::Plain);
+            self.emit_text(backend, encoder, glyphs, [x.start + padding, top], x.clone(), |_| fg_only)?;
+        }
+        Ok(())
+    }
+
+    // Adds the status line at the bottom of the window, see the status module. The
+    // command line gets a bar cursor at its end, and the right side is drawn over the
+    // left side if they don't both fit.
+    fn emit_status_line(
+        &mut self,
+        backend: &mut RenderBackend,
+        encoder: &mut wgpu::CommandEncoder,
+        state: &State,
+        text_face: Face,
+    ) -> IOResult<()> {
+        let top = self.status_top();
+        let clip = 0.0..self.window_size.0;
+        let status_face = text_face.with(state.faces.get("status-line"));
+        self.emit_background(clip.clone(), top..self.window_size.1, &clip, status_face);
+        let fg_only = Face { bg: None, ..status_face };
+
+        let status = state.status_line.format(state);
+        let status_glyphs = self.fonts.shape(&status, self.size_px, Shaping::Plain);
+        let status_width: f32 = status_glyphs.iter().map(|glyph| glyph.advance[0]).sum();
+        // Half a line of space at the right edge
+        let status_x = (self.window_size.0 - status_width - self.size_px / 2.).max(0.);
+        let left_clip = 0.0..status_x;
+
+        if state.mode == Mode::Command {
+            let prompt_face = fg_only.with(state.faces.get("prompt"));
+            let prompt = format!(":{}", state.command_line);
+            let glyphs = self.fonts.shape(&prompt, self.size_px, Shaping::Plain);
+            let cluster_positions = self.emit_text(backend, encoder, glyphs, [0., top], left_clip.clone(), |_| prompt_face)?;
+            let x = cluster_positions.last().map(|&(_, x)| x).unwrap_or(0.);
+            self.emit_background(x..x + CURSOR_BAR_WIDTH_PX, top..top + self.size_px, &left_clip, state.faces.get("primary-cursor"));
+        } else if let Some(message) = &state.message {
+            let face = if message.is_error { fg_only.with(state.faces.get("error")) } else { fg_only };
+            // Only the first line of a message fits
+            let text = message.text.lines().next().unwrap_or("");
+            let glyphs = self.fonts.shape(text, self.size_px, Shaping::Plain);
+            self.emit_text(backend, encoder, glyphs, [0., top], left_clip, |_| face)?;
+        }
+
+        self.emit_text(backend, encoder, status_glyphs, [status_x, top], clip, |_| fg_only)?;
+        Ok(())
+    }
+
+    pub(super) async fn upload(
+        &mut self,
+        backend: &mut RenderBackend,
+        state: &State,
+        glyph_vertex_buffer: &mut GrowableVertexBuffer,
+        quad_vertex_buffer: &mut GrowableVertexBuffer,
+    ) -> IOResult<Option<UploadedText>> {
+        let mut encoder = backend.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor {
+                label: Some("Texture upload encoder"),
+            }
         );
 
-        // Upload vertex data
-        let raw_data: &[u8] = bytemuck::cast_slice(&verticies);
+        self.atlas.begin_frame();
+
+        let viewport = &state.viewport;
+        let size_px = self.size_px;
+        let gutter_width = viewport.gutter_width();
+        let digit_width = self.text_width("0");
+        let gutter_clip = 0.0..gutter_width;
+        let info_box = state.info.as_ref().map(|info| self.info_box(info));
+
+        let cursor_line = state.content.byte_to_line(state.selections.primary().cursor);
+
+        // The background of the default face is the clear color of the window
+        let text_face = Face { bg: None, ..state.faces.get("default") };
+        let gutter_face = text_face.with(state.faces.get("gutter"));
+        let gutter_cursor_face = text_face.with(state.faces.get("gutter-cursor"));
+
+        // Lines are shaped separately, so that shaping doesn't depend on the size of the whole buffer.
+        // Only visible lines are shaped at all.
+        let visible_lines = viewport.visible_lines(state.content.len_lines());
+        for (line_idx, line) in visible_lines.clone().zip(state.content.lines_from(visible_lines.start)) {
+            let line_range = state.content.line_range(line_idx);
+            let top = (line_idx - visible_lines.start) as f32 * size_px;
+            let line_y = top..top + size_px;
+            let text_clip = match &info_box {
+                Some((info_x, info_y)) if info_y.start < line_y.end => gutter_width..info_x.start.max(gutter_width),
+                _ => gutter_width..self.window_size.0,
+            };
+
+            // Line numbers are right aligned, with a digit of space before the text. The line
+            // of the primary cursor is highlighted.
+            if let Some(number) = viewport.line_number(line_idx, cursor_line) {
+                let face = if line_idx == cursor_line { gutter_cursor_face } else { gutter_face };
+                self.emit_background(gutter_clip.clone(), line_y.clone(), &gutter_clip, face);
+
+                let number = number.to_string();
+                let x = gutter_width - digit_width * (number.len() + 1) as f32;
+                let fg_only = Face { bg: None, ..face };
+                let glyphs = self.fonts.shape(&number, size_px, Shaping::Plain);
+                self.emit_text(backend, &mut encoder, glyphs, [x, top], gutter_clip.clone(), |_| fg_only)?;
+            }
 
-        if raw_data.len() != 0 {
-            let mapped_write_fut = glyph_vertex_buffer.map_write(0, raw_data.len() as u64);
-            backend.device.poll(wgpu::Maintain::Wait);
-            let mut mapped_write = mapped_write_fut.await.map_err(|_| into_ioerror("Write sync error"))?;
+            let line_faces = state.highlights.overlapping(line_range.clone());
+            let glyphs = self.fonts.shape(&line, size_px, state.shaping);
+            let cluster_positions = self.emit_text(
+                backend,
+                &mut encoder,
+                glyphs,
+                [gutter_width - viewport.left, top],
+                text_clip.clone(),
+                |byte| line_faces.face_at(line_range.start + byte, text_face),
+            )?;
+            self.emit_selections(state, line_idx, &cluster_positions, line_y, &text_clip);
+        }
+
+        self.emit_status_line(backend, &mut encoder, state, text_face)?;
+        if let Some(info) = &state.info {
+            self.emit_info(backend, &mut encoder, info, text_face, state.faces.get("information"))?;
+        }
 
-            mapped_write.as_slice().copy_from_slice(raw_data);
+        let atlas_filled = self.atlas.overflowed && !self.atlas_was_full;
+        self.atlas_was_full = self.atlas.overflowed;
+        self.atlas.overflowed = false;
 
-            glyph_vertex_buffer.unmap();
+        let mut verticies: Vec<Vertex> = Vec::new();
+        let mut page_ranges = Vec::with_capacity(self.page_verticies.len());
+        for page in std::mem::take(&mut self.page_verticies) {
+            let start = verticies.len() as u32;
+            verticies.extend(page);
+            page_ranges.push(start..verticies.len() as u32);
         }
 
-        backend.queue.submit(&[encoder.finish()]);
+        // Upload vertex data
+        glyph_vertex_buffer.write(&backend.device, &verticies).await?;
 
-        self.text_rendered_cache = state.content.clone();
+        let mut quad_verticies = std::mem::take(&mut self.background_verticies);
+        let background_range = 0..quad_verticies.len() as u32;
+        quad_verticies.append(&mut self.decoration_verticies);
+        let decoration_range = background_range.end..quad_verticies.len() as u32;
+        quad_vertex_buffer.write(&backend.device, &quad_verticies).await?;
 
-        Ok(Some(verticies.len() as u32))
+        backend.queue.submit(&[encoder.finish()]);
+        self.atlas.uploads_submitted();
+
+        Ok(Some(UploadedText {
+            page_ranges,
+            background_range,
+            decoration_range,
+            atlas_filled,
+        }))
     }
 }
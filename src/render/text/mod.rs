@@ -1,8 +1,8 @@
 use std::io::Result as IOResult;
+use std::ops::Range;
 
 use wgpu::{
     RenderPipeline,
-    BufferUsage,
     BindGroupLayoutEntry,
     ShaderStage,
     BindingType,
@@ -14,50 +14,59 @@ use wgpu::{
     BlendOperation,
     ProgrammableStageDescriptor,
     BlendDescriptor,
-    TextureFormat,
-    Extent3d,
 };
 
 use super::{RenderBackend, RichTexture};
 #[allow(unused)]
 use crate::into_ioerror;
 use crate::state::State;
+use crate::viewport::Viewport;
+use crate::layout::FontConfig;
+use crate::options::{Options, Type, Value};
 
 mod text_gpu_primitives;
-use text_gpu_primitives::Vertex;
+use text_gpu_primitives::{Vertex, QuadVertex, GrowableVertexBuffer};
+
+mod atlas;
+mod packing;
+mod fonts;
+use fonts::FontChain;
 
 mod glypher;
-use glypher::Glypher;
+use glypher::{Glypher, HBLineLayout};
 
 const VS_DATA: &[u8] = include_bytes!("../../../compiled-shaders/text-vert.spv");
 const FS_DATA: &[u8] = include_bytes!("../../../compiled-shaders/text-frag.spv");
+const QUAD_VS_DATA: &[u8] = include_bytes!("../../../compiled-shaders/quad-vert.spv");
+const QUAD_FS_DATA: &[u8] = include_bytes!("../../../compiled-shaders/quad-frag.spv");
+
+const INITIAL_VERTEX_BUFFER_SIZE: u64 = 4096;
 
 pub(super) struct TextRenderer {
     render_pipeline: RenderPipeline,
-    bind_group: wgpu::BindGroup,
+    // Draws the selections and cursors behind the text
+    quad_pipeline: RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    // One for each glyph atlas page
+    page_bind_groups: Vec<wgpu::BindGroup>,
+
+    glyph_vertex_buffer: GrowableVertexBuffer,
+    // Verticies sampling from each atlas page
+    page_vertex_ranges: Vec<Range<u32>>,
 
-    glyph_canvas: RichTexture,
-    glyph_vertex_buffer: wgpu::Buffer,
-    n_verticies: u32,
+    quad_vertex_buffer: GrowableVertexBuffer,
+    // Quads drawn before and after the glyphs
+    background_vertex_range: Range<u32>,
+    decoration_vertex_range: Range<u32>,
+    // Whether the glyph atlas filled up since take_atlas_filled was last called
+    atlas_filled: bool,
 
     glypher: Glypher,
 }
 
 impl TextRenderer {
     pub async fn new(backend: &mut RenderBackend) -> IOResult<Self> {
-        let glyph_canvas = RichTexture::new(
-            backend,
-            TextureFormat::Rgba8UnormSrgb,
-            Extent3d {
-                width: 1024,
-                height: 1024,
-                depth: 1,
-            },
-            Some("Glyph canvas"),
-        )?;
-
-        let glyph_canvas_view = glyph_canvas.create_default_view();
-
         let sampler = backend.device.create_sampler(
             &wgpu::SamplerDescriptor {
                 address_mode_u: AddressMode::ClampToEdge,
@@ -72,13 +81,8 @@ impl TextRenderer {
             },
         );
 
-        let glyph_vertex_buffer = backend.device.create_buffer(
-            &wgpu::BufferDescriptor {
-                label: Some("Glyph vertex buffer"),
-                size: 4096, // For now
-                usage: BufferUsage::COPY_DST | BufferUsage::VERTEX | BufferUsage::MAP_WRITE,
-            },
-        );
+        let glyph_vertex_buffer = GrowableVertexBuffer::new(&backend.device, INITIAL_VERTEX_BUFFER_SIZE, "Glyph vertex buffer");
+        let quad_vertex_buffer = GrowableVertexBuffer::new(&backend.device, INITIAL_VERTEX_BUFFER_SIZE, "Quad vertex buffer");
 
         let bind_group_layout = backend.device.create_bind_group_layout(
             &wgpu::BindGroupLayoutDescriptor {
@@ -104,27 +108,6 @@ impl TextRenderer {
             },
         );
 
-        let bind_group = backend.device.create_bind_group(
-            &wgpu::BindGroupDescriptor {
-                label: Some("Text bind group"),
-                layout: &bind_group_layout,
-                bindings: &[
-                    Binding {
-                        binding: 0,
-                        resource: BindingResource::TextureView(
-                            &glyph_canvas_view,
-                        ),
-                    },
-                    Binding {
-                        binding: 1,
-                        resource: BindingResource::Sampler(
-                            &sampler,
-                        ),
-                    },
-                ],
-            },
-        );
-
         let pipeline_layout = backend.device.create_pipeline_layout(
             &wgpu::PipelineLayoutDescriptor {
                 bind_group_layouts: &[
@@ -133,12 +116,50 @@ impl TextRenderer {
             },
         );
 
-        let vs_module = backend.load_shader_mod(VS_DATA)?;
-        let fs_module = backend.load_shader_mod(FS_DATA)?;
+        let render_pipeline = Self::create_pipeline(backend, &pipeline_layout, VS_DATA, FS_DATA, Vertex::desc())?;
+
+        // Quads don't sample the atlas, so they need no bind groups
+        let quad_pipeline_layout = backend.device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[],
+            },
+        );
+        let quad_pipeline = Self::create_pipeline(backend, &quad_pipeline_layout, QUAD_VS_DATA, QUAD_FS_DATA, QuadVertex::desc())?;
+
+        let glypher = Glypher::new(backend)?;
+
+        let mut text_renderer = Self {
+            render_pipeline,
+            quad_pipeline,
+            bind_group_layout,
+            sampler,
+            page_bind_groups: Vec::new(),
+            glyph_vertex_buffer,
+            page_vertex_ranges: Vec::new(),
+            quad_vertex_buffer,
+            background_vertex_range: 0..0,
+            decoration_vertex_range: 0..0,
+            atlas_filled: false,
+            glypher,
+        };
+        text_renderer.create_page_bind_groups(backend);
+
+        Ok(text_renderer)
+    }
+
+    fn create_pipeline(
+        backend: &mut RenderBackend,
+        pipeline_layout: &wgpu::PipelineLayout,
+        vs_data: &[u8],
+        fs_data: &[u8],
+        vertex_desc: wgpu::VertexBufferDescriptor,
+    ) -> IOResult<RenderPipeline> {
+        let vs_module = backend.load_shader_mod(vs_data)?;
+        let fs_module = backend.load_shader_mod(fs_data)?;
 
         let render_pipeline = backend.device.create_render_pipeline(
             &wgpu::RenderPipelineDescriptor {
-                layout: &pipeline_layout,
+                layout: pipeline_layout,
                 vertex_stage: ProgrammableStageDescriptor {
                     module: &vs_module,
                     entry_point: "main",
@@ -174,7 +195,7 @@ impl TextRenderer {
                 vertex_state: wgpu::VertexStateDescriptor {
                     index_format: wgpu::IndexFormat::Uint32,
                     vertex_buffers: &[
-                        Vertex::desc(),
+                        vertex_desc,
                     ],
                 },
                 depth_stencil_state: None,
@@ -184,34 +205,107 @@ impl TextRenderer {
             },
         );
 
-        let glypher = Glypher::new()?;
+        Ok(render_pipeline)
+    }
 
-        Ok(Self {
-            render_pipeline,
-            bind_group,
-            glyph_canvas,
-            glyph_vertex_buffer,
-            n_verticies: 0,
-            glypher,
-        })
+    // Creates bind groups for atlas pages which were added since the last call
+    fn create_page_bind_groups(&mut self, backend: &mut RenderBackend) {
+        for page in &self.glypher.atlas_pages()[self.page_bind_groups.len()..] {
+            let canvas_view = page.canvas.create_default_view();
+
+            let bind_group = backend.device.create_bind_group(
+                &wgpu::BindGroupDescriptor {
+                    label: Some("Text bind group"),
+                    layout: &self.bind_group_layout,
+                    bindings: &[
+                        Binding {
+                            binding: 0,
+                            resource: BindingResource::TextureView(
+                                &canvas_view,
+                            ),
+                        },
+                        Binding {
+                            binding: 1,
+                            resource: BindingResource::Sampler(
+                                &self.sampler,
+                            ),
+                        },
+                    ],
+                },
+            );
+
+            self.page_bind_groups.push(bind_group);
+        }
+    }
+
+    pub fn declare_options(options: &mut Options) {
+        let declarations = [
+            ("atlas-page-size", Type::Int { min: 256, max: 8192 }, atlas::DEFAULT_PAGE_SIZE),
+            ("glyph-margin", Type::Int { min: 0, max: 16 }, atlas::DEFAULT_GLYPH_MARGIN),
+        ];
+        for (name, ty, default) in declarations.iter().cloned() {
+            options.declare(name, ty, Value::Int(default as i64)).expect("Text renderer options are declared once");
+        }
+
+        // Fonts which can't be loaded are refused when they're set, rather than when they're drawn
+        options.set_check("font", |value| match value {
+            Value::List(fonts) => {
+                let font_config = FontConfig { fonts: fonts.clone(), ..FontConfig::default() };
+                FontChain::load(&font_config).map(|_| ()).map_err(|e| e.to_string())
+            }
+            _ => Ok(()),
+        }).expect("font is a builtin option");
     }
 
     pub fn resize(&mut self, backend: &mut RenderBackend) -> IOResult<()> {
         self.glypher.resize(backend)
     }
 
+    /// Replaces the atlas pages with ones of another size, or with another margin around glyphs
+    pub fn set_atlas_layout(&mut self, backend: &mut RenderBackend, page_size: u32, glyph_margin: u32) -> IOResult<()> {
+        self.glypher.set_atlas_layout(backend, page_size, glyph_margin)?;
+        self.page_bind_groups.clear();
+        self.create_page_bind_groups(backend);
+        Ok(())
+    }
+
+    pub fn set_viewport_size(&self, viewport: &mut Viewport) {
+        self.glypher.set_viewport_size(viewport);
+    }
+
+    pub fn line_layout(&self) -> HBLineLayout {
+        self.glypher.line_layout()
+    }
+
+    pub fn font_config(&self) -> &FontConfig {
+        self.glypher.font_config()
+    }
+
+    pub fn set_font_config(&mut self, font_config: &FontConfig) -> IOResult<()> {
+        self.glypher.set_font_config(font_config)
+    }
+
+    pub fn take_atlas_filled(&mut self) -> bool {
+        std::mem::take(&mut self.atlas_filled)
+    }
+
     pub async fn write_data(&mut self, backend: &mut RenderBackend, state: &State) -> IOResult<()> {
-        if let Some(n) = self
+        if let Some(uploaded) = self
             .glypher
             .upload(
                 backend,
                 state,
                 &mut self.glyph_vertex_buffer,
-                &mut self.glyph_canvas,
+                &mut self.quad_vertex_buffer,
             )
             .await? {
-            self.n_verticies = n;
+            self.page_vertex_ranges = uploaded.page_ranges;
+            self.background_vertex_range = uploaded.background_range;
+            self.decoration_vertex_range = uploaded.decoration_range;
+            self.atlas_filled |= uploaded.atlas_filled;
         }
+        self.create_page_bind_groups(backend);
+
         Ok(())
     }
 
@@ -239,20 +333,39 @@ impl TextRenderer {
             }
         );
 
+        if self.background_vertex_range.start != self.background_vertex_range.end {
+            render_pass.set_pipeline(&self.quad_pipeline);
+            render_pass.set_vertex_buffer(0, &self.quad_vertex_buffer.buffer, 0, 0);
+            render_pass.draw(self.background_vertex_range.clone(), 0..1);
+        }
+
         render_pass.set_pipeline(&self.render_pipeline);
-        render_pass.set_vertex_buffer(0, &self.glyph_vertex_buffer, 0, 0);
-        render_pass.set_bind_group(0, &self.bind_group, &[]);
-        render_pass.draw(0..self.n_verticies, 0..1);
+        render_pass.set_vertex_buffer(0, &self.glyph_vertex_buffer.buffer, 0, 0);
+        for (bind_group, range) in self.page_bind_groups.iter().zip(&self.page_vertex_ranges) {
+            if range.start == range.end {
+                continue;
+            }
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.draw(range.clone(), 0..1);
+        }
+
+        if self.decoration_vertex_range.start != self.decoration_vertex_range.end {
+            render_pass.set_pipeline(&self.quad_pipeline);
+            render_pass.set_vertex_buffer(0, &self.quad_vertex_buffer.buffer, 0, 0);
+            render_pass.draw(self.decoration_vertex_range.clone(), 0..1);
+        }
 
         std::mem::drop(render_pass);
 
         Ok(encoder.finish())
     }
 
-    pub fn collect_textures<'a>(&'a self) -> Vec<&'a RichTexture> {
-        vec![
-            &self.glyph_canvas,
-        ]
+    pub fn collect_textures(&self) -> Vec<&RichTexture> {
+        self.glypher
+            .atlas_pages()
+            .iter()
+            .map(|page| &page.canvas)
+            .collect()
     }
 }
 
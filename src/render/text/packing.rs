@@ -0,0 +1,294 @@
+// Shelf packer for the glyph canvas. The canvas is split into horizontal
+// shelves, each of which holds rectangles of roughly the same height next to
+// each other. Freed rectangles are given back to their shelf, and shelves are
+// reused once they become empty.
+//
+// This replaces the summed area table approach in rectangular_packing_testing.py,
+// which packed tighter but was far too slow to run for every new glyph.
+
+use std::ops::Range;
+
+/// How much taller than a rectangle a shelf can be for the rectangle to be put in it
+const SHELF_HEIGHT_TOLERANCE: u32 = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PackedRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PackedRect {
+    pub fn area(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    #[cfg(test)]
+    pub fn overlaps(&self, other: &PackedRect) -> bool {
+        self.x < other.x + other.width && other.x < self.x + self.width &&
+            self.y < other.y + other.height && other.y < self.y + self.height
+    }
+}
+
+struct Shelf {
+    y: u32,
+    height: u32,
+    // Sorted, non-adjacent ranges of free x coordinates
+    free_spans: Vec<Range<u32>>,
+}
+
+impl Shelf {
+    fn new(y: u32, height: u32, width: u32) -> Self {
+        Self {
+            y,
+            height,
+            free_spans: vec![Range { start: 0, end: width }],
+        }
+    }
+
+    fn is_empty(&self, width: u32) -> bool {
+        self.free_spans.len() == 1 && self.free_spans[0] == (0..width)
+    }
+
+    fn find_span(&self, width: u32) -> Option<usize> {
+        // Best fit, to keep large spans around for wide glyphs
+        self.free_spans
+            .iter()
+            .enumerate()
+            .filter(|(_, span)| span.end - span.start >= width)
+            .min_by_key(|(_, span)| span.end - span.start)
+            .map(|(i, _)| i)
+    }
+
+    fn take(&mut self, span_idx: usize, width: u32) -> u32 {
+        let x = self.free_spans[span_idx].start;
+        self.free_spans[span_idx].start += width;
+        if self.free_spans[span_idx].start == self.free_spans[span_idx].end {
+            self.free_spans.remove(span_idx);
+        }
+        x
+    }
+
+    fn give_back(&mut self, span: Range<u32>) {
+        let idx = self.free_spans
+            .iter()
+            .position(|free| free.start > span.start)
+            .unwrap_or(self.free_spans.len());
+
+        self.free_spans.insert(idx, span);
+
+        // Merge with the next span, then the previous
+        if idx + 1 < self.free_spans.len() && self.free_spans[idx].end == self.free_spans[idx + 1].start {
+            self.free_spans[idx].end = self.free_spans[idx + 1].end;
+            self.free_spans.remove(idx + 1);
+        }
+        if idx > 0 && self.free_spans[idx - 1].end == self.free_spans[idx].start {
+            self.free_spans[idx - 1].end = self.free_spans[idx].end;
+            self.free_spans.remove(idx);
+        }
+    }
+}
+
+pub struct ShelfPacker {
+    width: u32,
+    height: u32,
+    // Sorted by y, without gaps between them
+    shelves: Vec<Shelf>,
+    used_area: u64,
+}
+
+impl ShelfPacker {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            shelves: Vec::new(),
+            used_area: 0,
+        }
+    }
+
+    /// Finds a place for a rectangle of the given size. Returns None if the canvas is full.
+    pub fn allocate(&mut self, width: u32, height: u32) -> Option<PackedRect> {
+        if width == 0 || height == 0 || width > self.width || height > self.height {
+            return None;
+        }
+
+        let canvas_width = self.width;
+
+        // Prefer the shelf which wastes the least height
+        let mut best: Option<(usize, usize)> = None;
+        for (shelf_idx, shelf) in self.shelves.iter().enumerate() {
+            if shelf.height < height {
+                continue;
+            }
+            if shelf.height - height > SHELF_HEIGHT_TOLERANCE && !shelf.is_empty(canvas_width) {
+                continue;
+            }
+            if let Some(span_idx) = shelf.find_span(width) {
+                let better = best
+                    .map(|(best_idx, _)| self.shelves[best_idx].height > shelf.height)
+                    .unwrap_or(true);
+                if better {
+                    best = Some((shelf_idx, span_idx));
+                }
+            }
+        }
+
+        if best.is_none() {
+            let top = self.shelves.last().map(|shelf| shelf.y + shelf.height).unwrap_or(0);
+            if top + height <= self.height {
+                self.shelves.push(Shelf::new(top, height, canvas_width));
+                best = Some((self.shelves.len() - 1, 0));
+            }
+        }
+
+        if best.is_none() {
+            // Out of vertical space, fall back to any shelf the rectangle fits in
+            best = self.shelves
+                .iter()
+                .enumerate()
+                .filter(|(_, shelf)| shelf.height >= height)
+                .filter_map(|(shelf_idx, shelf)| shelf.find_span(width).map(|span_idx| (shelf_idx, span_idx)))
+                .next();
+        }
+
+        let (shelf_idx, span_idx) = best?;
+        let shelf = &mut self.shelves[shelf_idx];
+        let x = shelf.take(span_idx, width);
+
+        let rect = PackedRect {
+            x,
+            y: shelf.y,
+            width,
+            height,
+        };
+        self.used_area += rect.area();
+
+        Some(rect)
+    }
+
+    /// Gives back a rectangle previously returned by allocate
+    pub fn free(&mut self, rect: PackedRect) {
+        let shelf_idx = if let Some(idx) = self.shelves.iter().position(|shelf| shelf.y == rect.y) {
+            idx
+        } else {
+            return;
+        };
+
+        self.shelves[shelf_idx].give_back(rect.x..rect.x + rect.width);
+        self.used_area -= rect.area();
+
+        // Empty shelves at the end can be given back to the canvas, so that they
+        // can be recreated with a different height
+        while self.shelves.last().map(|shelf| shelf.is_empty(self.width)).unwrap_or(false) {
+            self.shelves.pop();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.shelves.clear();
+        self.used_area = 0;
+    }
+
+    /// Ratio of the canvas area covered by allocated rectangles
+    #[cfg(test)]
+    pub fn fill_ratio(&self) -> f32 {
+        self.used_area as f32 / (self.width as u64 * self.height as u64) as f32
+    }
+}
+
+#[cfg(test)]
+fn glyph_like_sizes(font_size: u32, n: usize) -> Vec<(u32, u32)> {
+    // Simple deterministic LCG, so that the tests don't need a rand dependency
+    let mut seed: u32 = 12345;
+    let mut next = move || {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) % 1000
+    };
+
+    (0..n)
+        .map(|_| {
+            let width = font_size / 2 + next() * font_size / 1000;
+            let height = font_size * 3 / 4 + next() * font_size / 2000;
+            (width.max(4), height.max(4))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn fill(packer: &mut ShelfPacker, sizes: &[(u32, u32)]) -> Vec<PackedRect> {
+    sizes
+        .iter()
+        .filter_map(|&(width, height)| packer.allocate(width, height))
+        .collect()
+}
+
+#[test]
+fn test_packing_fill_ratio() {
+    let font_size = 20;
+    let mut packer = ShelfPacker::new(font_size * 16, font_size * 16);
+
+    let rects = fill(&mut packer, &glyph_like_sizes(font_size, 10000));
+
+    for (i, a) in rects.iter().enumerate() {
+        assert!(a.x + a.width <= font_size * 16 && a.y + a.height <= font_size * 16);
+        for b in &rects[i + 1..] {
+            assert!(!a.overlaps(b), "{:?} overlaps {:?}", a, b);
+        }
+    }
+
+    assert!(packer.fill_ratio() > 0.8, "Fill ratio was only {}", packer.fill_ratio());
+}
+
+#[test]
+fn test_packing_reports_full() {
+    let mut packer = ShelfPacker::new(64, 64);
+
+    assert_eq!(packer.allocate(65, 1), None);
+    assert!(packer.allocate(64, 64).is_some());
+    assert_eq!(packer.allocate(1, 1), None);
+}
+
+#[test]
+fn test_packing_fragmentation() {
+    let font_size = 20;
+    let mut packer = ShelfPacker::new(font_size * 16, font_size * 16);
+    let sizes = glyph_like_sizes(font_size, 10000);
+
+    let rects = fill(&mut packer, &sizes);
+    let full_ratio = packer.fill_ratio();
+
+    // Free every other rectangle, and check that the holes can mostly be filled again
+    for rect in rects.iter().step_by(2) {
+        packer.free(*rect);
+    }
+    assert!(packer.fill_ratio() < full_ratio * 0.6);
+
+    for rect in rects.iter().step_by(2) {
+        packer.allocate(rect.width, rect.height);
+    }
+    assert!(packer.fill_ratio() > full_ratio * 0.9, "Fill ratio went from {} to {}", full_ratio, packer.fill_ratio());
+
+    // Freeing everything should give back the whole canvas
+    packer.clear();
+    let refilled = fill(&mut packer, &sizes);
+    assert_eq!(refilled, rects);
+}
+
+#[test]
+fn test_packing_free_merges_spans() {
+    let mut packer = ShelfPacker::new(30, 10);
+
+    let a = packer.allocate(10, 10).unwrap();
+    let b = packer.allocate(10, 10).unwrap();
+    let c = packer.allocate(10, 10).unwrap();
+    assert_eq!(packer.allocate(10, 10), None);
+
+    packer.free(a);
+    packer.free(c);
+    assert_eq!(packer.allocate(20, 10), None);
+
+    packer.free(b);
+    assert_eq!(packer.allocate(30, 10), Some(PackedRect { x: 0, y: 0, width: 30, height: 10 }));
+}
@@ -1,27 +1,35 @@
 use std::mem::size_of;
+use std::io::Result as IOResult;
 
 use wgpu::{
+    Buffer,
+    BufferUsage,
+    Device,
     VertexBufferDescriptor,
     VertexAttributeDescriptor,
     VertexFormat,
 };
 
+use crate::into_ioerror;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct Vertex {
     pub position: [f32; 2],
     pub fontdata_uv: [f32; 2],
+    /// Multiplied with the glyph coverage from the atlas
+    pub color: [f32; 4],
 }
 
 unsafe impl bytemuck::Pod for Vertex {}
 unsafe impl bytemuck::Zeroable for Vertex {}
 
 impl Vertex {
-    pub fn create_quad(xy_0: [f32; 2], xy_1: [f32; 2], uv_0: [f32; 2], uv_1: [f32; 2]) -> [Vertex; 6] {
-        let tl = Vertex { position: [xy_0[0], xy_0[1]], fontdata_uv: [uv_0[0], uv_0[1]]};
-        let tr = Vertex { position: [xy_1[0], xy_0[1]], fontdata_uv: [uv_1[0], uv_0[1]]};
-        let bl = Vertex { position: [xy_0[0], xy_1[1]], fontdata_uv: [uv_0[0], uv_1[1]]};
-        let br = Vertex { position: [xy_1[0], xy_1[1]], fontdata_uv: [uv_1[0], uv_1[1]]};
+    pub fn create_quad(xy_0: [f32; 2], xy_1: [f32; 2], uv_0: [f32; 2], uv_1: [f32; 2], color: [f32; 4]) -> [Vertex; 6] {
+        let tl = Vertex { position: [xy_0[0], xy_0[1]], fontdata_uv: [uv_0[0], uv_0[1]], color };
+        let tr = Vertex { position: [xy_1[0], xy_0[1]], fontdata_uv: [uv_1[0], uv_0[1]], color };
+        let bl = Vertex { position: [xy_0[0], xy_1[1]], fontdata_uv: [uv_0[0], uv_1[1]], color };
+        let br = Vertex { position: [xy_1[0], xy_1[1]], fontdata_uv: [uv_1[0], uv_1[1]], color };
 
         [
             tl, bl, tr,
@@ -44,7 +52,116 @@ impl Vertex {
                     format: VertexFormat::Float2,
                     shader_location: 1,
                 },
+                VertexAttributeDescriptor { // color: [f32; 4], after position and fontdata_uv
+                    offset: 2 * size_of::<[f32; 2]>() as u64,
+                    format: VertexFormat::Float4,
+                    shader_location: 2,
+                },
             ],
         }
     }
 }
+
+/// Vertex of solid colored quads, for backgrounds, selections, cursors and lines under and through text
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct QuadVertex {
+    pub position: [f32; 2],
+    pub color: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for QuadVertex {}
+unsafe impl bytemuck::Zeroable for QuadVertex {}
+
+impl QuadVertex {
+    pub fn create_quad(xy_0: [f32; 2], xy_1: [f32; 2], color: [f32; 4]) -> [QuadVertex; 6] {
+        let tl = QuadVertex { position: [xy_0[0], xy_0[1]], color };
+        let tr = QuadVertex { position: [xy_1[0], xy_0[1]], color };
+        let bl = QuadVertex { position: [xy_0[0], xy_1[1]], color };
+        let br = QuadVertex { position: [xy_1[0], xy_1[1]], color };
+
+        [
+            tl, bl, tr,
+            br, tr, bl,
+        ]
+    }
+
+    pub fn desc<'a>() -> VertexBufferDescriptor<'a> {
+        VertexBufferDescriptor {
+            stride: size_of::<QuadVertex>() as u64,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                VertexAttributeDescriptor { // position: [f32; 2]
+                    offset: 0,
+                    format: VertexFormat::Float2,
+                    shader_location: 0,
+                },
+                VertexAttributeDescriptor { // color: [f32; 4]
+                    offset: size_of::<[f32; 2]>() as u64,
+                    format: VertexFormat::Float4,
+                    shader_location: 1,
+                },
+            ],
+        }
+    }
+}
+
+/// Vertex buffer which is recreated with twice the size whenever more verticies need to fit in it
+pub struct GrowableVertexBuffer {
+    pub buffer: Buffer,
+    size: u64,
+    label: &'static str,
+}
+
+impl GrowableVertexBuffer {
+    pub fn new(device: &Device, size: u64, label: &'static str) -> Self {
+        Self {
+            buffer: Self::create_buffer(device, size, label),
+            size,
+            label,
+        }
+    }
+
+    fn create_buffer(device: &Device, size: u64, label: &str) -> Buffer {
+        device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: BufferUsage::COPY_DST | BufferUsage::VERTEX | BufferUsage::MAP_WRITE,
+            },
+        )
+    }
+
+    fn ensure_size(&mut self, device: &Device, needed_size: u64) {
+        if needed_size <= self.size {
+            return;
+        }
+
+        let mut new_size = self.size.max(1);
+        while new_size < needed_size {
+            new_size *= 2;
+        }
+
+        self.buffer = Self::create_buffer(device, new_size, self.label);
+        self.size = new_size;
+    }
+
+    /// Writes the verticies to the start of the buffer, growing it if needed
+    pub async fn write<T: bytemuck::Pod>(&mut self, device: &Device, verticies: &[T]) -> IOResult<()> {
+        let raw_data: &[u8] = bytemuck::cast_slice(verticies);
+        if raw_data.is_empty() {
+            return Ok(());
+        }
+
+        self.ensure_size(device, raw_data.len() as u64);
+
+        let mapped_write_fut = self.buffer.map_write(0, raw_data.len() as u64);
+        device.poll(wgpu::Maintain::Wait);
+        let mut mapped_write = mapped_write_fut.await.map_err(|_| into_ioerror("Write sync error"))?;
+
+        mapped_write.as_slice().copy_from_slice(raw_data);
+
+        self.buffer.unmap();
+        Ok(())
+    }
+}
@@ -0,0 +1,560 @@
+// A rope for storing buffer contents. Text is stored in leaves of at most
+// MAX_LEAF_BYTES bytes, with byte, char and newline counts cached in every
+// branch so that indexing and edits are logarithmic in the size of the text.
+
+use std::ops::Range;
+use std::fmt;
+use std::io::{self, Write};
+
+const MAX_LEAF_BYTES: usize = 1024;
+
+// The tree is rebuilt when it gets deeper than this, which can happen after
+// lots of edits in the same place
+const MAX_DEPTH: usize = 32;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct TextInfo {
+    bytes: usize,
+    chars: usize,
+    newlines: usize,
+}
+
+impl TextInfo {
+    fn of(text: &str) -> Self {
+        Self {
+            bytes: text.len(),
+            chars: text.chars().count(),
+            newlines: text.bytes().filter(|&b| b == b'\n').count(),
+        }
+    }
+
+    fn combine(self, other: TextInfo) -> Self {
+        Self {
+            bytes: self.bytes + other.bytes,
+            chars: self.chars + other.chars,
+            newlines: self.newlines + other.newlines,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Leaf(String),
+    Branch {
+        left: Box<Node>,
+        right: Box<Node>,
+        info: TextInfo,
+        depth: usize,
+    },
+}
+
+impl Node {
+    fn branch(left: Node, right: Node) -> Node {
+        Node::Branch {
+            info: left.info().combine(right.info()),
+            depth: left.depth().max(right.depth()) + 1,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    // Builds a balanced tree out of leaves
+    fn from_leaves(mut leaves: Vec<String>) -> Node {
+        if leaves.len() <= 1 {
+            return Node::Leaf(leaves.pop().unwrap_or_default());
+        }
+        let right = leaves.split_off(leaves.len() / 2);
+        Node::branch(Node::from_leaves(leaves), Node::from_leaves(right))
+    }
+
+    fn info(&self) -> TextInfo {
+        match self {
+            Node::Leaf(text) => TextInfo::of(text),
+            Node::Branch { info, .. } => *info,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        match self {
+            Node::Leaf(_) => 0,
+            Node::Branch { depth, .. } => *depth,
+        }
+    }
+
+    fn update(&mut self) {
+        if let Node::Branch { left, right, info, depth } = self {
+            *info = left.info().combine(right.info());
+            *depth = left.depth().max(right.depth()) + 1;
+        }
+    }
+
+    fn insert(&mut self, byte_idx: usize, text: &str) {
+        match self {
+            Node::Leaf(leaf) => {
+                leaf.insert_str(byte_idx, text);
+                if leaf.len() > MAX_LEAF_BYTES {
+                    *self = Node::from_leaves(split_into_leaves(leaf));
+                }
+            }
+            Node::Branch { left, right, .. } => {
+                let left_bytes = left.info().bytes;
+                if byte_idx <= left_bytes {
+                    left.insert(byte_idx, text);
+                } else {
+                    right.insert(byte_idx - left_bytes, text);
+                }
+                self.update();
+            }
+        }
+    }
+
+    fn remove(&mut self, range: Range<usize>) {
+        match self {
+            Node::Leaf(leaf) => {
+                leaf.replace_range(range, "");
+            }
+            Node::Branch { left, right, .. } => {
+                let left_bytes = left.info().bytes;
+                if range.start < left_bytes {
+                    left.remove(range.start..range.end.min(left_bytes));
+                }
+                if range.end > left_bytes {
+                    right.remove(range.start.max(left_bytes) - left_bytes..range.end - left_bytes);
+                }
+                self.update();
+            }
+        }
+    }
+
+    fn collect_leaves(self, into: &mut Vec<String>) {
+        match self {
+            Node::Leaf(leaf) => {
+                // Merge small leaves together
+                if let Some(last) = into.last_mut() {
+                    if last.len() + leaf.len() <= MAX_LEAF_BYTES {
+                        last.push_str(&leaf);
+                        return;
+                    }
+                }
+                if !leaf.is_empty() {
+                    into.push(leaf);
+                }
+            }
+            Node::Branch { left, right, .. } => {
+                left.collect_leaves(into);
+                right.collect_leaves(into);
+            }
+        }
+    }
+
+    fn append_slice(&self, range: Range<usize>, into: &mut String) {
+        match self {
+            Node::Leaf(leaf) => into.push_str(&leaf[range]),
+            Node::Branch { left, right, .. } => {
+                let left_bytes = left.info().bytes;
+                if range.start < left_bytes {
+                    left.append_slice(range.start..range.end.min(left_bytes), into);
+                }
+                if range.end > left_bytes {
+                    right.append_slice(range.start.max(left_bytes) - left_bytes..range.end - left_bytes, into);
+                }
+            }
+        }
+    }
+
+    fn write_leaves(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Node::Leaf(leaf) => out.write_all(leaf.as_bytes()),
+            Node::Branch { left, right, .. } => {
+                left.write_leaves(out)?;
+                right.write_leaves(out)
+            }
+        }
+    }
+
+    fn leaf_at(&self, byte_idx: usize) -> (&str, usize) {
+        match self {
+            Node::Leaf(leaf) => (leaf, byte_idx),
+            Node::Branch { left, right, .. } => {
+                let left_bytes = left.info().bytes;
+                if byte_idx < left_bytes {
+                    left.leaf_at(byte_idx)
+                } else {
+                    right.leaf_at(byte_idx - left_bytes)
+                }
+            }
+        }
+    }
+
+    fn byte_to_char(&self, byte_idx: usize) -> usize {
+        match self {
+            Node::Leaf(leaf) => leaf[..byte_idx].chars().count(),
+            Node::Branch { left, right, .. } => {
+                let left_info = left.info();
+                if byte_idx < left_info.bytes {
+                    left.byte_to_char(byte_idx)
+                } else {
+                    left_info.chars + right.byte_to_char(byte_idx - left_info.bytes)
+                }
+            }
+        }
+    }
+
+    fn char_to_byte(&self, char_idx: usize) -> usize {
+        match self {
+            Node::Leaf(leaf) => leaf.char_indices().nth(char_idx).map(|(i, _)| i).unwrap_or(leaf.len()),
+            Node::Branch { left, right, .. } => {
+                let left_info = left.info();
+                if char_idx < left_info.chars {
+                    left.char_to_byte(char_idx)
+                } else {
+                    left_info.bytes + right.char_to_byte(char_idx - left_info.chars)
+                }
+            }
+        }
+    }
+
+    fn byte_to_line(&self, byte_idx: usize) -> usize {
+        match self {
+            Node::Leaf(leaf) => leaf[..byte_idx].bytes().filter(|&b| b == b'\n').count(),
+            Node::Branch { left, right, .. } => {
+                let left_info = left.info();
+                if byte_idx < left_info.bytes {
+                    left.byte_to_line(byte_idx)
+                } else {
+                    left_info.newlines + right.byte_to_line(byte_idx - left_info.bytes)
+                }
+            }
+        }
+    }
+
+    // Byte index just after the n:th newline
+    fn after_newline(&self, n: usize) -> usize {
+        match self {
+            Node::Leaf(leaf) => {
+                leaf.bytes()
+                    .enumerate()
+                    .filter(|&(_, b)| b == b'\n')
+                    .nth(n)
+                    .map(|(i, _)| i + 1)
+                    .unwrap_or(leaf.len())
+            }
+            Node::Branch { left, right, .. } => {
+                let left_info = left.info();
+                if n < left_info.newlines {
+                    left.after_newline(n)
+                } else {
+                    left_info.bytes + right.after_newline(n - left_info.newlines)
+                }
+            }
+        }
+    }
+}
+
+// Splits text into leaves of at most MAX_LEAF_BYTES, at char boundaries
+fn split_into_leaves(text: &str) -> Vec<String> {
+    let mut leaves = Vec::with_capacity(text.len() / MAX_LEAF_BYTES + 1);
+    let mut rest = text;
+    while rest.len() > MAX_LEAF_BYTES {
+        let mut split_at = MAX_LEAF_BYTES;
+        while !rest.is_char_boundary(split_at) {
+            split_at -= 1;
+        }
+        leaves.push(rest[..split_at].to_string());
+        rest = &rest[split_at..];
+    }
+    leaves.push(rest.to_string());
+    leaves
+}
+
+#[derive(Clone, Debug)]
+pub struct Rope {
+    root: Node,
+}
+
+impl Rope {
+    pub fn new() -> Self {
+        Self {
+            root: Node::Leaf(String::new()),
+        }
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        self.root.info().bytes
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.root.info().chars
+    }
+
+    /// Number of lines. A trailing newline starts a new, empty, line.
+    pub fn len_lines(&self) -> usize {
+        self.root.info().newlines + 1
+    }
+
+    pub fn insert(&mut self, byte_idx: usize, text: &str) {
+        assert!(self.is_char_boundary(byte_idx), "Insert at {} is not on a char boundary", byte_idx);
+        if text.is_empty() {
+            return;
+        }
+
+        self.root.insert(byte_idx, text);
+        self.rebalance_if_needed();
+    }
+
+    pub fn remove(&mut self, range: Range<usize>) {
+        assert!(
+            self.is_char_boundary(range.start) && self.is_char_boundary(range.end) && range.start <= range.end,
+            "Removing {:?} is not on char boundaries", range,
+        );
+        if range.start == range.end {
+            return;
+        }
+
+        self.root.remove(range);
+        self.rebalance_if_needed();
+    }
+
+    fn rebalance_if_needed(&mut self) {
+        if self.root.depth() > MAX_DEPTH {
+            let root = std::mem::replace(&mut self.root, Node::Leaf(String::new()));
+            let mut leaves = Vec::new();
+            root.collect_leaves(&mut leaves);
+            self.root = Node::from_leaves(leaves);
+        }
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> String {
+        let mut result = String::with_capacity(range.end - range.start);
+        if range.start < range.end {
+            self.root.append_slice(range, &mut result);
+        }
+        result
+    }
+
+    pub fn is_char_boundary(&self, byte_idx: usize) -> bool {
+        if byte_idx >= self.len_bytes() {
+            return byte_idx == self.len_bytes();
+        }
+        let (leaf, idx) = self.root.leaf_at(byte_idx);
+        leaf.is_char_boundary(idx)
+    }
+
+    /// The char starting at the byte index
+    pub fn char_at(&self, byte_idx: usize) -> Option<char> {
+        if byte_idx >= self.len_bytes() {
+            return None;
+        }
+        let (leaf, idx) = self.root.leaf_at(byte_idx);
+        leaf.get(idx..).and_then(|rest| rest.chars().next())
+    }
+
+    /// Byte index of the char before the byte index
+    pub fn prev_char_boundary(&self, byte_idx: usize) -> Option<usize> {
+        let char_idx = self.byte_to_char(byte_idx);
+        if char_idx == 0 {
+            None
+        } else {
+            Some(self.char_to_byte(char_idx - 1))
+        }
+    }
+
+    /// Byte index of the char after the one starting at the byte index
+    pub fn next_char_boundary(&self, byte_idx: usize) -> Option<usize> {
+        self.char_at(byte_idx).map(|ch| byte_idx + ch.len_utf8())
+    }
+
+    pub fn byte_to_char(&self, byte_idx: usize) -> usize {
+        self.root.byte_to_char(byte_idx.min(self.len_bytes()))
+    }
+
+    pub fn char_to_byte(&self, char_idx: usize) -> usize {
+        self.root.char_to_byte(char_idx.min(self.len_chars()))
+    }
+
+    /// The line containing the byte index
+    pub fn byte_to_line(&self, byte_idx: usize) -> usize {
+        self.root.byte_to_line(byte_idx.min(self.len_bytes()))
+    }
+
+    /// Byte index of the start of the line
+    pub fn line_to_byte(&self, line_idx: usize) -> usize {
+        if line_idx == 0 {
+            0
+        } else if line_idx >= self.len_lines() {
+            self.len_bytes()
+        } else {
+            self.root.after_newline(line_idx - 1)
+        }
+    }
+
+    /// Byte range of the line, not including the newline at the end
+    pub fn line_range(&self, line_idx: usize) -> Range<usize> {
+        let start = self.line_to_byte(line_idx);
+        let mut end = self.line_to_byte(line_idx + 1);
+        if end > start && self.char_at(end - 1) == Some('\n') {
+            end -= 1;
+        }
+        start..end
+    }
+
+    /// Contents of the line, not including the newline at the end
+    pub fn line(&self, line_idx: usize) -> String {
+        self.slice(self.line_range(line_idx))
+    }
+
+    /// The lines from line_idx to the end, walking the leaves once instead of
+    /// looking up every line from the root
+    pub fn lines_from(&self, line_idx: usize) -> Lines<'_> {
+        let mut byte_idx = self.line_to_byte(line_idx);
+        let mut rights = Vec::new();
+        let mut node = &self.root;
+        let rest = loop {
+            match node {
+                Node::Leaf(leaf) => break &leaf[byte_idx..],
+                Node::Branch { left, right, .. } => {
+                    let left_bytes = left.info().bytes;
+                    if byte_idx < left_bytes {
+                        rights.push(&**right);
+                        node = left;
+                    } else {
+                        byte_idx -= left_bytes;
+                        node = right;
+                    }
+                }
+            }
+        };
+        Lines {
+            rights,
+            rest,
+            remaining: self.len_lines().saturating_sub(line_idx),
+        }
+    }
+
+    /// Writes the whole text without collecting it into one string first
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        self.root.write_leaves(out)
+    }
+}
+
+/// Iterator over lines of a rope, not including the newlines at their ends
+pub struct Lines<'a> {
+    // Right children of the branches above the current leaf, innermost last
+    rights: Vec<&'a Node>,
+    // What is left of the current leaf
+    rest: &'a str,
+    remaining: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn next_leaf(&mut self) -> Option<&'a str> {
+        let mut node = self.rights.pop()?;
+        loop {
+            match node {
+                Node::Leaf(leaf) => return Some(leaf),
+                Node::Branch { left, right, .. } => {
+                    self.rights.push(right);
+                    node = left;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let mut line = String::new();
+        loop {
+            if let Some(newline) = self.rest.find('\n') {
+                line.push_str(&self.rest[..newline]);
+                self.rest = &self.rest[newline + 1..];
+                return Some(line);
+            }
+            line.push_str(self.rest);
+            self.rest = "";
+            match self.next_leaf() {
+                Some(leaf) => self.rest = leaf,
+                None => return Some(line),
+            }
+        }
+    }
+}
+
+impl From<&str> for Rope {
+    fn from(text: &str) -> Self {
+        Self {
+            root: Node::from_leaves(split_into_leaves(text)),
+        }
+    }
+}
+
+impl From<String> for Rope {
+    fn from(text: String) -> Self {
+        Rope::from(text.as_str())
+    }
+}
+
+impl fmt::Display for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.slice(0..self.len_bytes()))
+    }
+}
+
+#[test]
+fn test_rope_edits() {
+    let mut rope = Rope::from("hellå wörld");
+    rope.insert(7, "kära ");
+    assert_eq!(rope.to_string(), "hellå kära wörld");
+
+    rope.remove(0..7);
+    assert_eq!(rope.to_string(), "kära wörld");
+    assert_eq!(rope.len_chars(), 10);
+    assert_eq!(rope.len_bytes(), 12);
+}
+
+#[test]
+fn test_rope_many_edits_in_one_place() {
+    let mut rope = Rope::new();
+    let mut expected = String::new();
+    for i in 0..5000 {
+        let ch = if i % 7 == 0 { "\n" } else if i % 3 == 0 { "ö" } else { "a" };
+        let at = expected.len() / 2;
+        let at = (0..=at).rev().find(|&i| expected.is_char_boundary(i)).unwrap();
+        rope.insert(at, ch);
+        expected.insert_str(at, ch);
+    }
+    assert_eq!(rope.to_string(), expected);
+    assert!(rope.root.depth() <= MAX_DEPTH);
+
+    rope.remove(100..expected.len() - 100);
+    expected.replace_range(100..expected.len() - 100, "");
+    assert_eq!(rope.to_string(), expected);
+}
+
+#[test]
+fn test_rope_indexing() {
+    let text = "första\nandra\n\ntredje ö\n".repeat(500);
+    let rope = Rope::from(text.as_str());
+
+    assert_eq!(rope.len_lines(), text.lines().count() + 1);
+    for (line_idx, line) in text.lines().enumerate() {
+        assert_eq!(rope.line(line_idx), line);
+    }
+    assert_eq!(rope.line(rope.len_lines() - 1), "");
+    assert!(rope.lines_from(0).eq(text.split('\n').map(str::to_string)));
+    assert!(rope.lines_from(1001).eq(text.split('\n').skip(1001).map(str::to_string)));
+    assert_eq!(rope.lines_from(rope.len_lines()).count(), 0);
+
+    for (char_idx, (byte_idx, _)) in text.char_indices().enumerate().step_by(13) {
+        assert_eq!(rope.byte_to_char(byte_idx), char_idx);
+        assert_eq!(rope.char_to_byte(char_idx), byte_idx);
+        assert_eq!(rope.byte_to_line(byte_idx), text[..byte_idx].matches('\n').count());
+    }
+}
@@ -0,0 +1,193 @@
+use std::ops::Range;
+
+/// A selection of the bytes between the anchor and the cursor. The anchor stays
+/// put when a selection is extended, while the cursor moves. An empty selection
+/// is just a cursor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Selection {
+    pub anchor: usize,
+    pub cursor: usize,
+}
+
+impl Selection {
+    pub fn new(anchor: usize, cursor: usize) -> Self {
+        Self { anchor, cursor }
+    }
+
+    pub fn cursor(at: usize) -> Self {
+        Self::new(at, at)
+    }
+
+    pub fn start(&self) -> usize {
+        self.anchor.min(self.cursor)
+    }
+
+    pub fn end(&self) -> usize {
+        self.anchor.max(self.cursor)
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start()..self.end()
+    }
+
+    fn map(&self, f: impl Fn(usize) -> usize) -> Self {
+        Self::new(f(self.anchor), f(self.cursor))
+    }
+}
+
+/// An ordered set of non-overlapping selections, one of which is the primary selection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelectionSet {
+    // Sorted by start, never empty
+    selections: Vec<Selection>,
+    primary: usize,
+}
+
+impl SelectionSet {
+    pub fn new(selection: Selection) -> Self {
+        Self {
+            selections: vec![selection],
+            primary: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.selections.len()
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a Selection> + 'a {
+        self.selections.iter()
+    }
+
+    pub fn get(&self, idx: usize) -> Selection {
+        self.selections[idx]
+    }
+
+    pub fn set(&mut self, idx: usize, selection: Selection) {
+        self.selections[idx] = selection;
+    }
+
+    pub fn primary(&self) -> Selection {
+        self.selections[self.primary]
+    }
+
+    /// Whether the byte is inside any of the selections
+    #[cfg(test)]
+    pub fn contains(&self, byte_idx: usize) -> bool {
+        // Selections are sorted and non-overlapping, so their ends are sorted too
+        let idx = match self.selections.binary_search_by_key(&byte_idx, |sel| sel.start()) {
+            Ok(_) => return self.selections.iter().any(|sel| sel.range().contains(&byte_idx)),
+            Err(idx) => idx,
+        };
+        idx > 0 && self.selections[idx - 1].range().contains(&byte_idx)
+    }
+
+    /// Adds a selection, making it the primary one
+    pub fn push(&mut self, selection: Selection) {
+        self.selections.push(selection);
+        self.primary = self.selections.len() - 1;
+        self.normalize();
+    }
+
+    pub fn keep_primary(&mut self) {
+        self.selections = vec![self.primary()];
+        self.primary = 0;
+    }
+
+    pub fn rotate_primary(&mut self, forward: bool) {
+        let n = self.selections.len();
+        self.primary = if forward { (self.primary + 1) % n } else { (self.primary + n - 1) % n };
+    }
+
+    /// Applies f to every selection
+    pub fn transform(&mut self, mut f: impl FnMut(Selection) -> Selection) {
+        for sel in self.selections.iter_mut() {
+            *sel = f(*sel);
+        }
+        self.normalize();
+    }
+
+    /// Moves selections after an insertion of len bytes at the byte index, made
+    /// by the selection at edited_idx. Selections before it are left alone even
+    /// if they end at the insertion. The selections need to be normalized after
+    /// all edits are done.
+    pub fn shift_for_insert(&mut self, at: usize, len: usize, edited_idx: usize) {
+        for sel in self.selections[edited_idx..].iter_mut() {
+            *sel = sel.map(|x| if x >= at { x + len } else { x });
+        }
+    }
+
+    /// Moves selections after the bytes in range were removed. The selections
+    /// need to be normalized after all edits are done.
+    pub fn shift_for_remove(&mut self, range: Range<usize>) {
+        let len = range.end - range.start;
+        for sel in self.selections.iter_mut() {
+            *sel = sel.map(|x| {
+                if x >= range.end {
+                    x - len
+                } else if x > range.start {
+                    range.start
+                } else {
+                    x
+                }
+            });
+        }
+    }
+
+    /// Sorts the selections and merges overlapping ones
+    pub fn normalize(&mut self) {
+        let primary = self.primary();
+
+        self.selections.sort_by_key(|sel| (sel.start(), sel.end()));
+        self.primary = self.selections.iter().position(|&sel| sel == primary).unwrap_or(0);
+
+        let mut merged: Vec<Selection> = Vec::with_capacity(self.selections.len());
+        for (idx, sel) in self.selections.iter().enumerate() {
+            if let Some(last) = merged.last_mut() {
+                let overlaps = sel.start() < last.end() || sel.start() == last.start();
+                if overlaps {
+                    // Keep the direction of the earlier selection
+                    let start = last.start();
+                    let end = last.end().max(sel.end());
+                    *last = if last.cursor < last.anchor { Selection::new(end, start) } else { Selection::new(start, end) };
+                    if idx == self.primary {
+                        self.primary = merged.len() - 1;
+                    }
+                    continue;
+                }
+            }
+            if idx == self.primary {
+                self.primary = merged.len();
+            }
+            merged.push(*sel);
+        }
+        self.selections = merged;
+    }
+}
+
+#[test]
+fn test_selections_shift_and_merge() {
+    let mut sels = SelectionSet::new(Selection::new(0, 2));
+    sels.push(Selection::cursor(5));
+    sels.push(Selection::new(8, 6));
+    assert_eq!(sels.primary(), Selection::new(8, 6));
+
+    sels.shift_for_insert(5, 3, 1);
+    assert_eq!(sels.iter().cloned().collect::<Vec<_>>(), vec![Selection::new(0, 2), Selection::cursor(8), Selection::new(11, 9)]);
+
+    // Removing everything up to the last selection merges them all
+    sels.shift_for_remove(0..10);
+    sels.normalize();
+    assert_eq!(sels.len(), 1);
+    assert_eq!(sels.primary(), Selection::new(0, 1));
+}
+
+#[test]
+fn test_selections_contains() {
+    let mut sels = SelectionSet::new(Selection::new(2, 4));
+    sels.push(Selection::cursor(6));
+    sels.push(Selection::new(10, 8));
+
+    let inside: Vec<usize> = (0..12).filter(|&i| sels.contains(i)).collect();
+    assert_eq!(inside, vec![2, 3, 8, 9]);
+}
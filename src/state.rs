@@ -1,75 +1,1014 @@
 use std::ops::Range;
+use std::rc::Rc;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{Result as IOResult, ErrorKind, BufWriter, Write};
+
+use crate::rope::Rope;
+use crate::selection::{Selection, SelectionSet};
+use crate::motion;
+use crate::history::{History, Edit};
+use crate::layout::{self, LineLayout, Monospace, Shaping, FontConfig};
+use crate::viewport::{Viewport, LineNumbers};
+use crate::style::{Faces, FacedRanges};
+use crate::highlight::{self, Grammar, Highlighter};
+use crate::theme;
+use crate::command::{self, Commands};
+use crate::status::StatusLine;
+use crate::keymap::{Keymaps, Mapping};
+use crate::options::{Changes, Options, Scope};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Keys are commands
+    Normal,
+    /// Keys are inserted at the cursors
+    Insert,
+    /// Keys are typed into the command line, which is run with enter
+    Command,
+}
 
 pub struct State {
-    pub content: String,
-    pub cursor_range: Range<usize>,
+    pub content: Rope,
+    pub selections: SelectionSet,
+    pub mode: Mode,
+    pub history: History,
+    // One string for each selection which was yanked
+    yanked: Vec<String>,
+    /// The file the buffer is written to, or None for a scratch buffer
+    pub path: Option<PathBuf>,
+    // The revision which was last read from or written to the file
+    saved_revision: usize,
+    pub command_line: String,
+    /// The result of the last command, shown to the user
+    pub message: Option<Message>,
+    pub quit_requested: bool,
+    /// How lines are laid out when displayed, for vertical motions
+    pub layout: Box<dyn LineLayout>,
+    pub shaping: Shaping,
+    /// The fonts the renderer should use. It reloads its fonts when this changes.
+    pub font: FontConfig,
+    // The x position each cursor tries to stay at when moving between lines, and the
+    // selections after the last vertical motion. Only used while the selections are unchanged.
+    goal_columns: Option<(SelectionSet, Vec<f32>)>,
+    pub viewport: Viewport,
+    /// Faces for ranges of the visible lines, drawn on top of the face of plain text
+    pub highlights: FacedRanges,
+    pub faces: Faces,
+    pub grammars: Vec<Rc<Grammar>>,
+    highlighter: Highlighter,
+    pub commands: Commands,
+    pub status_line: StatusLine,
+    pub keymaps: Keymaps,
+    // Keys which are the start of a longer mapping, and for how long they have been waiting
+    pending_keys: Vec<Key>,
+    pending_time: f32,
+    /// How long to wait for the rest of a mapping before handling the keys typed so far, in seconds
+    pub key_timeout: f32,
+    /// Text shown in a popup, e.g. the mappings which can follow the keys typed so far
+    pub info: Option<String>,
+    /// Set by the debug-dump command, for the renderer to write out its textures
+    pub debug_dump_requested: bool,
+    /// Settings changed with set-option. Fields such as the fonts and the status line are kept in sync with them.
+    pub options: Options,
+    option_changes: Changes,
+}
+
+/// Something shown to the user, like the result of a command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub text: String,
+    pub is_error: bool,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Key {
     Typed(char),
+    Escape,
     Backspace,
-    ArrowLeft, ArrowRight,
-}
-
-fn char_index_before(st: &str, ch_idx: usize) -> Option<usize> {
-    if ch_idx == 0 {
-        return None;
-    }
-    for ch_byte_len in 1..=ch_idx {
-        if st.get(ch_idx - ch_byte_len .. ch_idx).is_some() {
-            return Some(ch_idx - ch_byte_len);
-        }
-    }
-    None
+    ArrowLeft, ArrowRight, ArrowUp, ArrowDown,
+    PageUp, PageDown,
+    /// A character typed while alt was held
+    Alt(char),
+    /// A character typed while ctrl was held
+    Ctrl(char),
 }
 
 #[test]
 fn test_char_index_before() {
-    let st = "hellå wörld";
+    let st = Rope::from("hellå wörld");
     let space_idx = 6;
-    assert_eq!(st.get(space_idx..space_idx+1), Some(" "));
+    assert_eq!(st.slice(space_idx..space_idx+1), " ");
+
+    assert_eq!(st.prev_char_boundary(space_idx), Some(space_idx - 2)); // å is two bytes
+    assert_eq!(st.prev_char_boundary(space_idx - 2), Some(space_idx - 3)); // l is one byte
+}
 
-    assert_eq!(char_index_before(st, space_idx), Some(space_idx - 2)); // å is two bytes
-    assert_eq!(char_index_before(st, space_idx - 2), Some(space_idx - 3)); // l is one byte
+// The contents of a file, and a message if it doesn't exist yet
+fn read_file(path: &Path) -> IOResult<(Rope, String)> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok((Rope::from(text), String::new())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok((Rope::new(), "New file".to_string())),
+        Err(e) => Err(e),
+    }
 }
 
 impl State {
-    pub fn new(content: String) -> State {
-        State {
+    pub fn new(content: Rope) -> State {
+        let selections = SelectionSet::new(Selection::cursor(0));
+        let mut options = Options::builtin();
+        let option_changes = options.subscribe();
+        let mut state = State {
             content,
-            cursor_range: 3..5,
+            history: History::new(selections.clone()),
+            selections,
+            mode: Mode::Normal,
+            yanked: Vec::new(),
+            path: None,
+            saved_revision: 0,
+            command_line: String::new(),
+            message: None,
+            quit_requested: false,
+            layout: Box::new(Monospace { advance: 1. }),
+            shaping: Shaping::Plain,
+            font: FontConfig::default(),
+            goal_columns: None,
+            viewport: Viewport::new(),
+            highlights: FacedRanges::new(),
+            faces: Faces::builtin(),
+            grammars: highlight::builtin_grammars(),
+            highlighter: Highlighter::new(None),
+            commands: Commands::builtin(),
+            status_line: StatusLine::default(),
+            keymaps: Keymaps::builtin(),
+            pending_keys: Vec::new(),
+            pending_time: 0.,
+            key_timeout: 1.,
+            info: None,
+            debug_dump_requested: false,
+            options,
+            option_changes,
+        };
+        state.apply_option_changes();
+        state
+    }
+
+    /// Replaces the buffer with a file. A file which doesn't exist yet gives an
+    /// empty buffer, and is created when the buffer is written. This is refused
+    /// if there are unsaved changes, unless forced.
+    pub fn edit(&mut self, path: &Path, force: bool) -> Result<String, String> {
+        if self.is_dirty() && !force {
+            return Err("Unsaved changes, use edit! to discard them".to_string());
+        }
+        let (content, message) = read_file(path).map_err(|e| format!("Couldn't open {}: {}", path.display(), e))?;
+
+        let selections = SelectionSet::new(Selection::cursor(0));
+        self.content = content;
+        self.history = History::new(selections.clone());
+        self.selections = selections;
+        self.saved_revision = self.history.current();
+        self.goal_columns = None;
+        self.viewport.top_line = 0;
+        self.viewport.left = 0.;
+        self.set_path(path);
+        // Buffer options belonged to the old buffer
+        self.options.clear_scope(Scope::Buffer);
+        self.apply_option_changes();
+        Ok(message)
+    }
+
+    // Sets the file of the buffer, and highlights it with the grammar for the file name
+    fn set_path(&mut self, path: &Path) {
+        self.highlighter = Highlighter::new(highlight::grammar_for_path(&self.grammars, path));
+        self.path = Some(path.to_path_buf());
+    }
+
+    pub fn step(&mut self, dt: f32) {
+        if !self.pending_keys.is_empty() {
+            self.pending_time += dt;
+            if self.pending_time >= self.key_timeout {
+                self.info = None;
+                self.resolve_pending_keys(true);
+            }
+        }
+    }
+
+    /// Whether there are changes which haven't been written to the file
+    pub fn is_dirty(&self) -> bool {
+        self.history.current() != self.saved_revision || self.history.has_pending_edits()
+    }
+
+    /// The path of the file, or *scratch*
+    pub fn buffer_name(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => "*scratch*".to_string(),
+        }
+    }
+
+    /// What the window title should be
+    pub fn title(&self) -> String {
+        format!("{}{} - rakoune", self.buffer_name(), if self.is_dirty() { " [+]" } else { "" })
+    }
+
+    fn write_file(&self, path: &Path) -> IOResult<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.content.write_to(&mut out)?;
+        out.flush()
+    }
+
+    /// Writes the buffer to its own file
+    pub fn write(&mut self) -> Result<String, String> {
+        let path = self.path.clone().ok_or_else(|| "No file name, use write-as <path>".to_string())?;
+        self.write_file(&path).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+        self.saved_revision = self.history.current();
+        Ok(format!("Wrote {} bytes to {}", self.content.len_bytes(), path.display()))
+    }
+
+    /// Writes a copy of the buffer, without changing which file the buffer belongs to
+    pub fn write_copy(&self, path: &Path) -> Result<String, String> {
+        self.write_file(path).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+        Ok(format!("Wrote {} bytes to {}", self.content.len_bytes(), path.display()))
+    }
+
+    /// Writes the buffer to another file, which it then belongs to
+    pub fn write_as(&mut self, path: &Path) -> Result<String, String> {
+        let old_path = self.path.replace(path.to_path_buf());
+        let result = self.write();
+        if result.is_err() {
+            self.path = old_path;
+        } else {
+            self.set_path(path);
+        }
+        result
+    }
+
+    /// Asks to quit, which is refused if there are unsaved changes unless forced
+    pub fn quit(&mut self, force: bool) -> Result<String, String> {
+        if self.is_dirty() && !force {
+            return Err("Unsaved changes, use quit! to discard them".to_string());
+        }
+        self.quit_requested = true;
+        Ok(String::new())
+    }
+
+    /// Runs a command line such as "write-as notes.txt", see the command module.
+    /// Stops at the first command which fails. Returns the message of the last
+    /// command which had one, or the error message.
+    pub fn run_command(&mut self, command_line: &str) -> Result<String, String> {
+        let mut message = String::new();
+        for invocation in command::parse(command_line)? {
+            let result = self.run_invocation(&invocation.words)?;
+            if !result.is_empty() {
+                message = result;
+            }
         }
+        Ok(message)
     }
 
-    pub fn step(&mut self, _dt: f32) {
+    fn run_invocation(&mut self, words: &[String]) -> Result<String, String> {
+        let (name, args) = words.split_first().expect("Commands have words");
+        let command = self.commands.get(name).ok_or_else(|| format!("Unknown command {}", name))?;
+        command.run(self, args)
     }
 
+    /// Runs the commands of a file, such as the rakrc. Commands which fail don't
+    /// stop the ones after them, and their errors are returned with their lines.
+    pub fn source(&mut self, path: &Path) -> Result<String, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let mut errors = Vec::new();
+        for result in command::parse_file(&text) {
+            let error = match result {
+                Ok(invocation) => self.run_invocation(&invocation.words).err().map(|e| (invocation.line, e)),
+                Err(error) => Some(error),
+            };
+            if let Some((line, e)) = error {
+                errors.push(format!("{}: line {}: {}", path.display(), line + 1, e));
+            }
+        }
+        if errors.is_empty() {
+            Ok(String::new())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// Sets an option, such as the fonts, from the words of a set-option command
+    pub fn set_option(&mut self, scope: Scope, name: &str, values: &[String]) -> Result<String, String> {
+        let value = self.options.parse_value(name, values)?;
+        self.options.set(scope, name, value)?;
+        self.apply_option_changes();
+        Ok(String::new())
+    }
+
+    pub fn unset_option(&mut self, scope: Scope, name: &str) -> Result<String, String> {
+        self.options.unset(scope, name)?;
+        self.apply_option_changes();
+        Ok(String::new())
+    }
+
+    // Copies the options which changed into the fields which use them
+    fn apply_option_changes(&mut self) {
+        for name in self.option_changes.take() {
+            match name.as_str() {
+                "line-numbers" => {
+                    self.viewport.line_numbers = match self.options.str("line-numbers") {
+                        "off" => LineNumbers::Off,
+                        "relative" => LineNumbers::Relative,
+                        _ => LineNumbers::Absolute,
+                    };
+                }
+                "sitelen-pona" => {
+                    self.shaping = if self.options.bool("sitelen-pona") { Shaping::SitelenPona } else { Shaping::Plain };
+                    // Display columns change with the shaping
+                    self.goal_columns = None;
+                }
+                "font" => self.font.fonts = self.options.list("font").to_vec(),
+                "font-size" => self.font.size_px = self.options.int("font-size") as f32,
+                "key-timeout" => self.key_timeout = self.options.int("key-timeout") as f32 / 1000.,
+                "status-line" => {
+                    self.status_line = StatusLine::parse(self.options.str("status-line")).expect("status-line is checked when set");
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn set_theme(&mut self, name: &str) -> Result<String, String> {
+        self.faces = theme::load(name)?;
+        self.update_highlights();
+        Ok(String::new())
+    }
+
+    /// Shows the result of a command to the user
+    pub fn report(&mut self, result: Result<String, String>) {
+        match result {
+            Ok(text) if text.is_empty() => self.message = None,
+            Ok(text) => self.message = Some(Message { text, is_error: false }),
+            Err(text) => self.message = Some(Message { text, is_error: true }),
+        }
+    }
+
+    // Inserts text for the selection at sel_idx, moving the selections after it
+    fn insert(&mut self, sel_idx: usize, at: usize, text: &str) {
+        self.content.insert(at, text);
+        self.highlighter.invalidate_from(self.content.byte_to_line(at));
+        self.selections.shift_for_insert(at, text.len(), sel_idx);
+        self.history.record(Edit::Insert { at, text: text.to_string() });
+    }
+
+    fn remove(&mut self, range: Range<usize>) {
+        if range.start == range.end {
+            return;
+        }
+        let removed = self.content.slice(range.clone());
+        self.content.remove(range.clone());
+        self.highlighter.invalidate_from(self.content.byte_to_line(range.start));
+        self.selections.shift_for_remove(range.clone());
+        self.history.record(Edit::Remove { at: range.start, text: removed });
+    }
+
+    fn undo(&mut self) {
+        if let Some(selections) = self.history.undo(&mut self.content) {
+            self.selections = selections;
+        }
+        self.invalidate_history_changes();
+    }
+
+    fn redo(&mut self) {
+        if let Some(selections) = self.history.redo(&mut self.content) {
+            self.selections = selections;
+        }
+        self.invalidate_history_changes();
+    }
+
+    // Forgets the highlights of what undo and redo changed
+    fn invalidate_history_changes(&mut self) {
+        if let Some(changed_from) = self.history.take_changed_from() {
+            self.highlighter.invalidate_from(self.content.byte_to_line(changed_from));
+        }
+    }
+
+    /// Moves the buffer to any revision in the undo tree
+    pub fn jump_to_revision(&mut self, revision: usize) {
+        if let Some(selections) = self.history.jump_to(revision, &mut self.content) {
+            self.selections = selections;
+        }
+        self.invalidate_history_changes();
+    }
+
+    // Calls f for every selection index, last first, so that edits made for one
+    // selection don't move the selections not yet edited
+    fn for_each_selection(&mut self, mut f: impl FnMut(&mut Self, usize)) {
+        for sel_idx in (0..self.selections.len()).rev() {
+            f(self, sel_idx);
+        }
+        self.selections.normalize();
+    }
+
+    fn insert_at_cursors(&mut self, text: &str) {
+        self.for_each_selection(|state, sel_idx| {
+            let cursor = state.selections.get(sel_idx).cursor;
+            state.insert(sel_idx, cursor, text);
+        });
+    }
+
+    fn delete_before_cursors(&mut self) {
+        self.for_each_selection(|state, sel_idx| {
+            let cursor = state.selections.get(sel_idx).cursor;
+            if let Some(idx_before) = state.content.prev_char_boundary(cursor) {
+                state.remove(idx_before..cursor);
+            }
+        });
+    }
+
+    fn yank_selections(&mut self) {
+        let content = &self.content;
+        self.yanked = self.selections
+            .iter()
+            .map(|sel| content.slice(sel.range()))
+            .collect();
+    }
+
+    fn delete_selections(&mut self) {
+        self.for_each_selection(|state, sel_idx| {
+            let range = state.selections.get(sel_idx).range();
+            state.remove(range);
+        });
+    }
+
+    fn paste_after_selections(&mut self) {
+        if self.yanked.is_empty() {
+            return;
+        }
+
+        let yanked = self.yanked.clone();
+        self.for_each_selection(|state, sel_idx| {
+            // Selections without a yanked string of their own get the last one
+            let text = &yanked[sel_idx.min(yanked.len() - 1)];
+            let at = state.selections.get(sel_idx).end();
+            state.insert(sel_idx, at, text);
+            state.selections.set(sel_idx, Selection::new(at, at + text.len()));
+        });
+    }
+
+    fn open_line_below(&mut self) {
+        self.for_each_selection(|state, sel_idx| {
+            let line = state.content.byte_to_line(state.selections.get(sel_idx).end());
+            let line_end = state.content.line_range(line).end;
+            state.insert(sel_idx, line_end, "\n");
+            state.selections.set(sel_idx, Selection::cursor(line_end + 1));
+        });
+    }
+
+    fn select_lines(&mut self) {
+        let content = &self.content;
+        self.selections.transform(|sel| {
+            let first_line = content.byte_to_line(sel.start());
+            let last_line = content.byte_to_line(sel.end());
+            Selection::new(content.line_to_byte(first_line), content.line_to_byte(last_line + 1))
+        });
+    }
+
+    fn copy_selections_below(&mut self) {
+        let content = &self.content;
+        let copies: Vec<Selection> = self.selections
+            .iter()
+            .filter_map(|sel| {
+                let first_line = content.byte_to_line(sel.start());
+                let last_line = content.byte_to_line(sel.end());
+                let height = last_line - first_line + 1;
+                if last_line + height >= content.len_lines() {
+                    return None;
+                }
+                Some(Selection::new(
+                    motion::offset_lines(content, sel.anchor, height as isize),
+                    motion::offset_lines(content, sel.cursor, height as isize),
+                ))
+            })
+            .collect();
+
+        for copy in copies {
+            self.selections.push(copy);
+        }
+    }
+
+    // Moves the cursor of every selection. If extend is false, the selections become
+    // empty at the new cursor position.
+    fn move_cursors(&mut self, extend: bool, f: impl Fn(&Rope, usize) -> usize) {
+        let content = &self.content;
+        self.selections.transform(|sel| {
+            let cursor = f(content, sel.cursor);
+            if extend {
+                Selection::new(sel.anchor, cursor)
+            } else {
+                Selection::cursor(cursor)
+            }
+        });
+    }
+
+    // Selects from the cursor of every selection to a new position. If extend is
+    // true, the anchor is kept instead.
+    fn select_to(&mut self, extend: bool, f: impl Fn(&Rope, usize) -> usize) {
+        let content = &self.content;
+        self.selections.transform(|sel| {
+            let cursor = f(content, sel.cursor);
+            if extend {
+                Selection::new(sel.anchor, cursor)
+            } else {
+                Selection::new(sel.cursor, cursor)
+            }
+        });
+    }
+
+    // Moves the cursors between lines, keeping them at the same display column.
+    // The column is remembered across consecutive vertical motions, so moving
+    // through a short line doesn't move the cursor to the left for good.
+    fn move_vertically(&mut self, extend: bool, line_offset: isize) {
+        let content = &self.content;
+        let layout = &*self.layout;
+        let shaping = self.shaping;
+
+        let columns = match self.goal_columns.take() {
+            Some((selections, columns)) if selections == self.selections => columns,
+            _ => self.selections
+                .iter()
+                .map(|sel| {
+                    let line = content.byte_to_line(sel.cursor);
+                    let line_start = content.line_to_byte(line);
+                    layout::x_of_byte(layout, shaping, &content.line(line), sel.cursor - line_start)
+                })
+                .collect(),
+        };
+
+        let mut sel_idx = 0;
+        self.selections.transform(|sel| {
+            let line = content.byte_to_line(sel.cursor);
+            let target_line = (line as isize + line_offset).max(0).min(content.len_lines() as isize - 1) as usize;
+            let cursor = content.line_to_byte(target_line) + layout::byte_at_x(layout, shaping, &content.line(target_line), columns[sel_idx]);
+            sel_idx += 1;
+
+            if extend {
+                Selection::new(sel.anchor, cursor)
+            } else {
+                Selection::cursor(cursor)
+            }
+        });
+
+        // Selections which were merged lose their goal columns
+        if self.selections.len() == columns.len() {
+            self.goal_columns = Some((self.selections.clone(), columns));
+        }
+    }
+
+    // Moves the cursors and the viewport by the number of lines which fit in the viewport
+    fn move_page(&mut self, down: bool) {
+        let page = self.viewport.full_lines().max(1) as isize;
+        let offset = if down { page } else { -page };
+        self.viewport.scroll_lines(offset, self.content.len_lines());
+        self.move_vertically(false, offset);
+    }
+
+    /// Scrolls the viewport without moving the cursors, e.g. with the mouse wheel
+    pub fn scroll(&mut self, lines: f32, px: f32) {
+        self.viewport.scroll_partial_lines(lines, self.content.len_lines());
+        self.viewport.scroll_horizontally(px);
+        self.update_highlights();
+    }
+
+    /// Fits the gutter to the buffer and scrolls the viewport so that the
+    /// cursor of the primary selection is visible
+    pub fn update_viewport(&mut self) {
+        let digits = self.viewport.gutter_digits(self.content.len_lines());
+        let gutter_width = if digits == 0 {
+            0.
+        } else {
+            // One digit wide space between the numbers and the text
+            let digits = "0".repeat(digits + 1);
+            layout::x_of_byte(&*self.layout, Shaping::Plain, &digits, digits.len())
+        };
+        self.viewport.set_gutter_width(gutter_width);
+
+        let cursor = self.selections.primary().cursor;
+        let line = self.content.byte_to_line(cursor);
+        let line_start = self.content.line_to_byte(line);
+        let x = layout::x_of_byte(&*self.layout, self.shaping, &self.content.line(line), cursor - line_start);
+        self.viewport.scroll_to_show(line, x);
+        self.update_highlights();
+    }
+
+    // Highlights the visible lines
+    fn update_highlights(&mut self) {
+        let visible = self.viewport.visible_lines(self.content.len_lines());
+        self.highlights = self.highlighter.highlights(&self.content, visible, &self.faces);
+    }
+
+    fn char_left(content: &Rope, pos: usize) -> usize {
+        content.prev_char_boundary(pos).unwrap_or(pos)
+    }
+
+    fn char_right(content: &Rope, pos: usize) -> usize {
+        content.next_char_boundary(pos).unwrap_or(pos)
+    }
+
+    /// Handles a key through the keymap of the current mode. Keys which could
+    /// be the start of a mapping wait for the next key, or for the key timeout.
     pub fn received_key(&mut self, key: Key) {
+        self.info = None;
+        self.pending_keys.push(key);
+        self.pending_time = 0.;
+        self.resolve_pending_keys(false);
+
+        if !self.pending_keys.is_empty() {
+            let completions = self.keymaps.get(self.mode).completions(&self.pending_keys);
+            let lines: Vec<String> = completions.iter().map(|(keys, description)| format!("{}  {}", keys, description)).collect();
+            self.info = Some(lines.join("\n"));
+        }
+    }
+
+    // Runs the mappings of the pending keys, from the longest mapping at the
+    // start, and handles keys which aren't mapped by themselves. Unless timed
+    // out, keys which could still become a longer mapping are kept pending.
+    fn resolve_pending_keys(&mut self, timed_out: bool) {
+        while !self.pending_keys.is_empty() {
+            let keymap = self.keymaps.get(self.mode);
+            if !timed_out && keymap.has_longer(&self.pending_keys) {
+                return;
+            }
+
+            let mapped = (1..=self.pending_keys.len())
+                .rev()
+                .find_map(|len| keymap.get(&self.pending_keys[..len]).map(|binding| (len, binding.mapping.clone())));
+            match mapped {
+                Some((len, mapping)) => {
+                    self.pending_keys.drain(..len);
+                    self.run_mapping(mapping);
+                }
+                None => {
+                    let key = self.pending_keys.remove(0);
+                    self.handle_key(key);
+                }
+            }
+        }
+    }
+
+    fn run_mapping(&mut self, mapping: Mapping) {
+        match mapping {
+            Mapping::Keys(keys) => {
+                for key in keys {
+                    self.handle_key(key);
+                }
+            }
+            Mapping::Command(command_line) => {
+                let result = self.run_command(&command_line);
+                self.report(result);
+                self.update_viewport();
+            }
+        }
+    }
+
+    // Does what a key does in the current mode, without mapping it
+    fn handle_key(&mut self, key: Key) {
+        // Every normal mode command is its own undo group, while everything
+        // typed in insert mode is grouped together with the command which
+        // entered insert mode
+        self.history.begin_group(&self.selections);
+
+        match self.mode {
+            Mode::Normal => self.normal_mode_key(key),
+            Mode::Insert => self.insert_mode_key(key),
+            Mode::Command => self.command_mode_key(key),
+        }
+
+        if self.mode != Mode::Insert {
+            self.history.end_group(&self.selections);
+        }
+
+        self.update_viewport();
+    }
+
+    fn command_mode_key(&mut self, key: Key) {
         match key {
-            Key::Typed(ch) => {
-                self.content.insert(self.cursor_range.start, ch);
-                self.cursor_range.start += ch.len_utf8();
-                self.cursor_range.end += ch.len_utf8();
+            Key::Typed('\n') => {
+                self.mode = Mode::Normal;
+                let command_line = std::mem::take(&mut self.command_line);
+                let result = self.run_command(&command_line);
+                self.report(result);
             }
+            Key::Typed(ch) => self.command_line.push(ch),
             Key::Backspace => {
-                if let Some(idx_before) = char_index_before(&self.content, self.cursor_range.start) {
-                    let removed = self.content.remove(idx_before);
-
-                    self.cursor_range.start -= removed.len_utf8();
-                    self.cursor_range.end -= removed.len_utf8();
+                if self.command_line.pop().is_none() {
+                    self.mode = Mode::Normal;
                 }
             }
+            Key::Escape => {
+                self.command_line.clear();
+                self.mode = Mode::Normal;
+            }
+            Key::ArrowLeft | Key::ArrowRight | Key::ArrowUp | Key::ArrowDown |
+            Key::PageUp | Key::PageDown | Key::Alt(_) | Key::Ctrl(_) => {}
+        }
+    }
+
+    fn insert_mode_key(&mut self, key: Key) {
+        match key {
+            Key::Typed(ch) => {
+                self.insert_at_cursors(ch.encode_utf8(&mut [0; 4]));
+            }
+            Key::Escape => {
+                self.mode = Mode::Normal;
+            }
+            Key::Backspace => {
+                self.delete_before_cursors();
+            }
             Key::ArrowRight => {
-                if let Some(char_to_jump_over) = self.content[self.cursor_range.start..].chars().next() {
-                    self.cursor_range.start += char_to_jump_over.len_utf8();
-                }
+                self.move_cursors(false, State::char_right);
             }
             Key::ArrowLeft => {
-                if let Some(before_idx) = char_index_before(&self.content, self.cursor_range.start) {
-                    self.cursor_range.start = before_idx;
-                }
+                self.move_cursors(false, State::char_left);
+            }
+            Key::ArrowUp => self.move_vertically(false, -1),
+            Key::ArrowDown => self.move_vertically(false, 1),
+            Key::PageUp => self.move_page(false),
+            Key::PageDown => self.move_page(true),
+            Key::Alt(_) | Key::Ctrl(_) => {}
+        }
+    }
+
+    fn normal_mode_key(&mut self, key: Key) {
+        let ch = match key {
+            Key::Typed(ch) => ch,
+            Key::ArrowLeft => 'h',
+            Key::ArrowRight => 'l',
+            Key::ArrowUp => 'k',
+            Key::ArrowDown => 'j',
+            Key::PageUp | Key::PageDown => {
+                self.move_page(key == Key::PageDown);
+                return;
+            }
+            Key::Alt('u') => {
+                // Moves through the history in the order revisions were made, across branches
+                self.jump_to_revision(self.history.current().saturating_sub(1));
+                return;
+            }
+            Key::Alt('U') => {
+                self.jump_to_revision(self.history.current() + 1);
+                return;
+            }
+            Key::Escape | Key::Backspace | Key::Alt(_) | Key::Ctrl(_) => return,
+        };
+
+        match ch {
+            'h' | 'H' => self.move_cursors(ch == 'H', State::char_left),
+            'l' | 'L' => self.move_cursors(ch == 'L', State::char_right),
+            'j' | 'J' => self.move_vertically(ch == 'J', 1),
+            'k' | 'K' => self.move_vertically(ch == 'K', -1),
+            'w' | 'W' => self.select_to(ch == 'W', motion::next_word_start),
+            'e' | 'E' => self.select_to(ch == 'E', motion::word_end),
+            'b' | 'B' => self.select_to(ch == 'B', motion::prev_word_start),
+            'x' => self.select_lines(),
+            '%' => {
+                self.selections = SelectionSet::new(Selection::new(0, self.content.len_bytes()));
+            }
+            'C' => self.copy_selections_below(),
+            ',' => self.selections.keep_primary(),
+            '(' => self.selections.rotate_primary(false),
+            ')' => self.selections.rotate_primary(true),
+            'y' => self.yank_selections(),
+            'd' => {
+                self.yank_selections();
+                self.delete_selections();
+            }
+            'c' => {
+                self.yank_selections();
+                self.delete_selections();
+                self.mode = Mode::Insert;
             }
+            'p' => self.paste_after_selections(),
+            'u' => self.undo(),
+            'U' => self.redo(),
+            'i' => {
+                // Typed text is inserted before the selections
+                self.selections.transform(|sel| Selection::new(sel.end(), sel.start()));
+                self.mode = Mode::Insert;
+            }
+            'a' => {
+                // Typed text is inserted after the selections
+                self.selections.transform(|sel| Selection::new(sel.start(), sel.end()));
+                self.mode = Mode::Insert;
+            }
+            'o' => {
+                self.open_line_below();
+                self.mode = Mode::Insert;
+            }
+            ':' => {
+                self.message = None;
+                self.mode = Mode::Command;
+            }
+            _ => {}
         }
     }
 }
+
+#[test]
+fn test_normal_mode_editing() {
+    let mut state = State::new(Rope::from("ni li ilo\npi pana sitelen"));
+    state.selections = SelectionSet::new(Selection::cursor(0));
+
+    for ch in "wd".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    assert_eq!(state.content.to_string(), "li ilo\npi pana sitelen");
+
+    // Insert "mi " at the start of both lines
+    for ch in "Cimi ".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    state.received_key(Key::Escape);
+    assert_eq!(state.content.to_string(), "mi li ilo\nmi pi pana sitelen");
+    assert_eq!(state.selections.len(), 2);
+
+    for ch in ",xd".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    assert_eq!(state.content.to_string(), "mi li ilo\n");
+}
+
+#[test]
+fn test_undo_insert_session() {
+    let mut state = State::new(Rope::from("ilo"));
+    state.selections = SelectionSet::new(Selection::cursor(0));
+
+    for ch in "ini li ".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    state.received_key(Key::Backspace);
+    for ch in " pona ".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    state.received_key(Key::Escape);
+    assert_eq!(state.content.to_string(), "ni li pona ilo");
+
+    // The whole insert session is undone at once
+    state.received_key(Key::Typed('u'));
+    assert_eq!(state.content.to_string(), "ilo");
+    assert_eq!(state.selections.primary(), Selection::cursor(0));
+
+    state.received_key(Key::Typed('U'));
+    assert_eq!(state.content.to_string(), "ni li pona ilo");
+}
+
+#[test]
+fn test_write_and_dirty() {
+    let path = std::env::temp_dir().join(format!("rakoune-test-{}.txt", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut state = State::new(Rope::new());
+    assert_eq!(state.edit(&path, false), Ok("New file".to_string()));
+    assert_eq!(state.content.to_string(), "");
+    assert!(!state.is_dirty());
+
+    for ch in "itoki\n".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    assert!(state.is_dirty());
+    state.received_key(Key::Escape);
+
+    // Quitting with unsaved changes is refused
+    for ch in ":q\n".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    assert!(!state.quit_requested);
+    assert!(state.message.is_some());
+
+    for ch in ":w\n".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    assert!(!state.is_dirty());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "toki\n");
+
+    // Undoing past the written revision makes the buffer dirty again
+    state.received_key(Key::Typed('u'));
+    assert!(state.is_dirty());
+    state.received_key(Key::Typed('U'));
+    assert!(!state.is_dirty());
+
+    for ch in ":wq\n".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    assert!(state.quit_requested);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_commands() {
+    let mut state = State::new(Rope::new());
+    assert_eq!(state.run_command("echo 'toki pona'; echo %{a b} # c"), Ok("a b".to_string()));
+    assert_eq!(state.run_command("set-option font 'Fira Code' %{linja pona}"), Ok(String::new()));
+    assert_eq!(state.font.fonts, vec!["Fira Code", "linja pona"]);
+    assert_eq!(state.run_command("set line-numbers relative"), Ok(String::new()));
+    assert_eq!(state.viewport.line_numbers, LineNumbers::Relative);
+    assert_eq!(state.run_command("declare-option enum(a|b) nasa b; set window nasa a"), Ok(String::new()));
+    assert_eq!(state.options.str("nasa"), "a");
+
+    assert_eq!(state.run_command("write-as"), Err("Usage: write-as <path>".to_string()));
+    assert_eq!(state.run_command("nasa; echo a"), Err("Unknown command nasa".to_string()));
+
+    // The rest of the editor can add commands
+    state.commands.register("count-lines", &[], "count-lines", 0..=0, |state, _| Ok(state.content.len_lines().to_string()));
+    assert_eq!(state.run_command("count-lines"), Ok("1".to_string()));
+
+    let path = std::env::temp_dir().join(format!("rakoune-test-edit-{}.txt", std::process::id()));
+    fs::write(&path, "pona").unwrap();
+    for ch in "iike".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    state.received_key(Key::Escape);
+    state.run_command("set-option buffer line-numbers off").unwrap();
+    assert_eq!(state.viewport.line_numbers, LineNumbers::Off);
+    assert!(state.run_command(&format!("edit '{}'", path.display())).is_err());
+    assert_eq!(state.run_command(&format!("edit! '{}'", path.display())), Ok(String::new()));
+    assert_eq!(state.content.to_string(), "pona");
+    // Buffer options don't carry over to the new buffer
+    assert_eq!(state.viewport.line_numbers, LineNumbers::Relative);
+    state.run_command("unset-option line-numbers").unwrap();
+    assert_eq!(state.viewport.line_numbers, LineNumbers::Absolute);
+    assert!(!state.is_dirty());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_key_mappings() {
+    let mut state = State::new(Rope::from("ni li pona"));
+    state.run_command("map normal gw wd; map -docstring 'say toki' -command normal gt %{echo toki}").unwrap();
+
+    state.received_key(Key::Typed('g'));
+    assert_eq!(state.info, Some("t  say toki\nw  wd".to_string()));
+    state.received_key(Key::Typed('w'));
+    assert_eq!(state.content.to_string(), "li pona");
+    assert_eq!(state.info, None);
+
+    for ch in "gt".chars() {
+        state.received_key(Key::Typed(ch));
+    }
+    assert_eq!(state.message.as_ref().map(|message| message.text.as_str()), Some("toki"));
+
+    // Keys which can't finish a mapping are handled as they are, as are keys which could
+    // once the timeout is over
+    state.received_key(Key::Typed('g'));
+    state.step(state.key_timeout / 2.);
+    state.received_key(Key::Typed('x'));
+    assert_eq!(state.selections.primary(), Selection::new(0, 7));
+
+    state.run_command("map normal wd d").unwrap();
+    state.selections = SelectionSet::new(Selection::cursor(0));
+    state.received_key(Key::Typed('w'));
+    state.step(state.key_timeout);
+    assert_eq!(state.selections.primary(), Selection::new(0, 3));
+}
+
+#[test]
+fn test_source() {
+    let path = std::env::temp_dir().join(format!("rakoune-test-rakrc-{}", std::process::id()));
+    fs::write(&path, "set-option font-size 20\nnasa\n\nset-option line-numbers %{\n  sin\n}\nset line-numbers off").unwrap();
+
+    let mut state = State::new(Rope::new());
+    let errors = state.source(&path).unwrap_err();
+    assert_eq!(errors.lines().collect::<Vec<_>>(), vec![
+        format!("{}: line 2: Unknown command nasa", path.display()),
+        format!("{}: line 4: line-numbers is one of off, absolute, relative", path.display()),
+    ]);
+    // The commands around the errors still ran
+    assert_eq!(state.font.size_px, 20.);
+    assert_eq!(state.viewport.line_numbers, LineNumbers::Off);
+
+    // An unterminated block doesn't stop the commands before or after it
+    fs::write(&path, "set-option font-size 30\nset-option status-line %{\n{mode}\nset line-numbers relative").unwrap();
+    let errors = state.source(&path).unwrap_err();
+    assert_eq!(errors.lines().collect::<Vec<_>>(), vec![
+        format!("{}: line 2: unterminated %{{", path.display()),
+        format!("{}: line 3: Unknown command {{mode}}", path.display()),
+    ]);
+    assert_eq!(state.font.size_px, 30.);
+    assert_eq!(state.viewport.line_numbers, LineNumbers::Relative);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_vertical_motion_goal_column() {
+    let mut state = State::new(Rope::from("ilo pona\nni\nsitelen"));
+    state.selections = SelectionSet::new(Selection::cursor(6));
+
+    state.received_key(Key::Typed('j'));
+    assert_eq!(state.selections.primary(), Selection::cursor(11)); // End of "ni"
+    state.received_key(Key::ArrowDown);
+    assert_eq!(state.selections.primary(), Selection::cursor(18));
+
+    // Moving sideways forgets the column
+    state.received_key(Key::Typed('h'));
+    state.received_key(Key::Typed('k'));
+    state.received_key(Key::Typed('k'));
+    assert_eq!(state.selections.primary(), Selection::cursor(5));
+}
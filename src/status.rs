@@ -0,0 +1,121 @@
+// The status line at the bottom of the window. Its left side shows the command
+// line while it's being typed, and the last message otherwise. Its right side
+// is built from segments, written as a format like "{buffer}{dirty} {mode}"
+// where the names in braces are replaced by what they stand for.
+
+use crate::state::{State, Mode};
+
+pub const DEFAULT_FORMAT: &str = "{buffer}{dirty} {mode} {line}:{column} {selections} sel";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Field {
+    /// The file name, or *scratch*
+    Buffer,
+    /// [+] if there are unsaved changes
+    Dirty,
+    Mode,
+    /// The line of the primary cursor, from 1
+    Line,
+    /// The column of the primary cursor in chars, from 1
+    Column,
+    /// How many selections there are
+    Selections,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        Some(match name {
+            "buffer" => Field::Buffer,
+            "dirty" => Field::Dirty,
+            "mode" => Field::Mode,
+            "line" => Field::Line,
+            "column" => Field::Column,
+            "selections" => Field::Selections,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatusLine {
+    segments: Vec<Segment>,
+}
+
+impl StatusLine {
+    pub fn parse(format: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = format;
+        while let Some(open) = rest.find('{') {
+            let close = rest[open..].find('}').ok_or_else(|| format!("Unclosed {{ in {}", format))? + open;
+            let name = &rest[open + 1..close];
+            let field = Field::from_name(name).ok_or_else(|| format!("Unknown status line field {}", name))?;
+            if open > 0 {
+                segments.push(Segment::Text(rest[..open].to_string()));
+            }
+            segments.push(Segment::Field(field));
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        Ok(Self { segments })
+    }
+
+    /// The right side of the status line for the state
+    pub fn format(&self, state: &State) -> String {
+        let cursor = state.selections.primary().cursor;
+        let line = state.content.byte_to_line(cursor);
+        let line_start = state.content.line_to_byte(line);
+
+        let mut text = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(segment_text) => text.push_str(segment_text),
+                Segment::Field(Field::Buffer) => text.push_str(&state.buffer_name()),
+                Segment::Field(Field::Dirty) => text.push_str(if state.is_dirty() { " [+]" } else { "" }),
+                Segment::Field(Field::Mode) => text.push_str(match state.mode {
+                    Mode::Normal => "normal",
+                    Mode::Insert => "insert",
+                    Mode::Command => "command",
+                }),
+                Segment::Field(Field::Line) => text.push_str(&(line + 1).to_string()),
+                Segment::Field(Field::Column) => {
+                    let column = state.content.slice(line_start..cursor).chars().count() + 1;
+                    text.push_str(&column.to_string());
+                }
+                Segment::Field(Field::Selections) => text.push_str(&state.selections.len().to_string()),
+            }
+        }
+        text
+    }
+}
+
+impl Default for StatusLine {
+    fn default() -> Self {
+        Self::parse(DEFAULT_FORMAT).expect("The default status line is valid")
+    }
+}
+
+#[test]
+fn test_status_line() {
+    use crate::rope::Rope;
+    use crate::selection::{Selection, SelectionSet};
+
+    let mut state = State::new(Rope::from("ni li\nsitelen"));
+    state.selections = SelectionSet::new(Selection::cursor(0));
+    state.selections.push(Selection::cursor(8));
+    assert_eq!(StatusLine::default().format(&state), "*scratch* normal 2:3 2 sel");
+
+    let status_line = StatusLine::parse("[{mode}]").unwrap();
+    state.mode = Mode::Insert;
+    assert_eq!(status_line.format(&state), "[insert]");
+
+    assert_eq!(StatusLine::parse("{line"), Err("Unclosed { in {line".to_string()));
+    assert_eq!(StatusLine::parse("{lines}"), Err("Unknown status line field lines".to_string()));
+}
@@ -0,0 +1,231 @@
+// How text is drawn. A face sets colors and attributes for a piece of text, and
+// faces can be put on top of each other, e.g. a selection on top of a keyword.
+
+use std::ops::Range;
+use std::collections::HashMap;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl Color {
+    pub const fn rgba(r: f32, g: f32, b: f32, a: f32) -> Self {
+        Self { r, g, b, a }
+    }
+
+    pub const fn rgb(r: f32, g: f32, b: f32) -> Self {
+        Self::rgba(r, g, b, 1.)
+    }
+
+    pub fn as_array(&self) -> [f32; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+
+    /// Parses rgb:RRGGBB or rgba:RRGGBBAA
+    pub fn parse(text: &str) -> Result<Color, String> {
+        let hex = if let Some(hex) = text.strip_prefix("rgb:").filter(|hex| hex.len() == 6) {
+            format!("{}ff", hex)
+        } else if let Some(hex) = text.strip_prefix("rgba:").filter(|hex| hex.len() == 8) {
+            hex.to_string()
+        } else {
+            return Err(format!("Invalid color {}, expected rgb:RRGGBB or rgba:RRGGBBAA", text));
+        };
+
+        let mut channels = [0.; 4];
+        for (idx, channel) in channels.iter_mut().enumerate() {
+            let byte = hex.get(idx * 2..idx * 2 + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("Invalid color {}", text))?;
+            *channel = byte as f32 / 255.;
+        }
+        Ok(Color::rgba(channels[0], channels[1], channels[2], channels[3]))
+    }
+}
+
+/// Colors and attributes of text. Colors which are None are taken from the face below.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub struct Face {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub underline: bool,
+    pub strikethrough: bool,
+}
+
+impl Face {
+    pub const fn new(fg: Option<Color>, bg: Option<Color>) -> Self {
+        Self {
+            fg,
+            bg,
+            underline: false,
+            strikethrough: false,
+        }
+    }
+
+    /// Parses <fg>[,<bg>][+<attributes>], where colors can be default to keep
+    /// the color below, and the attributes are u for underline and s for strikethrough
+    pub fn parse(text: &str) -> Result<Face, String> {
+        let (colors, attributes) = match text.find('+') {
+            Some(plus) => (&text[..plus], &text[plus + 1..]),
+            None => (text, ""),
+        };
+
+        let color = |text: &str| if text == "default" { Ok(None) } else { Color::parse(text).map(Some) };
+        let mut colors = colors.splitn(2, ',');
+        let mut face = Face::new(color(colors.next().unwrap_or("default"))?, color(colors.next().unwrap_or("default"))?);
+
+        for attribute in attributes.chars() {
+            match attribute {
+                'u' => face.underline = true,
+                's' => face.strikethrough = true,
+                _ => return Err(format!("Unknown attribute {} in {}", attribute, text)),
+            }
+        }
+        Ok(face)
+    }
+
+    /// This face with other put on top of it
+    pub fn with(self, other: Face) -> Face {
+        Face {
+            fg: other.fg.or(self.fg),
+            bg: other.bg.or(self.bg),
+            underline: self.underline || other.underline,
+            strikethrough: self.strikethrough || other.strikethrough,
+        }
+    }
+}
+
+pub const DEFAULT_THEME: &str = include_str!("../resources/themes/default.theme");
+
+/// Faces by name, e.g. "comment", which is how highlighters and the renderer
+/// refer to faces. Themes set them.
+#[derive(Clone, Debug)]
+pub struct Faces {
+    faces: HashMap<String, Face>,
+}
+
+impl Faces {
+    /// The faces of the default theme
+    pub fn builtin() -> Self {
+        let mut faces = Faces { faces: HashMap::new() };
+        faces.apply_theme(DEFAULT_THEME).expect("The default theme is valid");
+        faces
+    }
+
+    /// Sets the faces from a theme file, which has a line "<name> <face>" for
+    /// each face. Faces the theme doesn't mention are kept.
+    pub fn apply_theme(&mut self, theme: &str) -> Result<(), String> {
+        for (line_idx, line) in theme.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(name), Some(face), None) => {
+                    let face = Face::parse(face).map_err(|e| format!("line {}: {}", line_idx + 1, e))?;
+                    self.set(name, face);
+                }
+                _ => return Err(format!("line {}: expected <name> <face>", line_idx + 1)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, name: &str, face: Face) {
+        self.faces.insert(name.to_string(), face);
+    }
+
+    /// The face with the name, or a face which changes nothing if there is none
+    pub fn get(&self, name: &str) -> Face {
+        self.faces.get(name).copied().unwrap_or_default()
+    }
+}
+
+/// Faces for ranges of bytes in the buffer. Ranges added later are put on top
+/// of earlier ones where they overlap.
+#[derive(Clone, Debug, Default)]
+pub struct FacedRanges {
+    ranges: Vec<(Range<usize>, Face)>,
+}
+
+impl FacedRanges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, range: Range<usize>, face: Face) {
+        if range.start < range.end {
+            self.ranges.push((range, face));
+        }
+    }
+
+    /// The ranges overlapping a range, e.g. a line, which are the only ones
+    /// face_at needs to look at for bytes in it
+    pub fn overlapping(&self, range: Range<usize>) -> FacedRanges {
+        FacedRanges {
+            ranges: self.ranges
+                .iter()
+                .filter(|(faced, _)| faced.start < range.end && range.start < faced.end)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// The face of a byte, with the faces of the ranges containing it put on top of base
+    pub fn face_at(&self, byte_idx: usize, base: Face) -> Face {
+        self.ranges
+            .iter()
+            .filter(|(range, _)| range.contains(&byte_idx))
+            .fold(base, |face, &(_, on_top)| face.with(on_top))
+    }
+}
+
+#[test]
+fn test_faced_ranges() {
+    let red = Color::rgb(1., 0., 0.);
+    let blue = Color::rgb(0., 0., 1.);
+    let white = Color::rgb(1., 1., 1.);
+
+    let mut ranges = FacedRanges::new();
+    ranges.push(0..10, Face::new(Some(red), None));
+    ranges.push(5..15, Face { underline: true, ..Face::new(None, Some(blue)) });
+
+    let base = Face::new(Some(white), None);
+    assert_eq!(ranges.face_at(2, base), Face::new(Some(red), None));
+    assert_eq!(ranges.face_at(7, base), Face { underline: true, ..Face::new(Some(red), Some(blue)) });
+    assert_eq!(ranges.face_at(12, base).fg, Some(white));
+    assert_eq!(ranges.face_at(20, base), base);
+
+    // Only the second range overlaps
+    assert_eq!(ranges.overlapping(10..12).face_at(7, base), Face { underline: true, ..Face::new(Some(white), Some(blue)) });
+}
+
+#[test]
+fn test_themes() {
+    assert_eq!(Color::parse("rgb:ff0000"), Ok(Color::rgb(1., 0., 0.)));
+    assert_eq!(Color::parse("rgba:00000000"), Ok(Color::rgba(0., 0., 0., 0.)));
+    assert!(Color::parse("rgb:ff00").is_err());
+    assert_eq!(Face::parse("default,rgb:0000ff+us"), Ok(Face { underline: true, strikethrough: true, ..Face::new(None, Some(Color::rgb(0., 0., 1.))) }));
+
+    let mut faces = Faces::builtin();
+    faces.apply_theme("# Only the comments\ncomment rgb:00ff00+u\n").unwrap();
+    assert_eq!(faces.get("comment"), Face { underline: true, ..Face::new(Some(Color::rgb(0., 1., 0.)), None) });
+    assert!(faces.get("keyword").fg.is_some());
+    assert_eq!(faces.apply_theme("\nkeyword blue"), Err("line 2: Invalid color blue, expected rgb:RRGGBB or rgba:RRGGBBAA".to_string()));
+}
+
+#[test]
+fn test_default_theme() {
+    let mut faces = Faces { faces: HashMap::new() };
+    assert_eq!(faces.apply_theme(DEFAULT_THEME), Ok(()));
+    assert!(faces.get("default").fg.is_some() && faces.get("default").bg.is_some());
+    assert_eq!(faces.get("selection").fg, None);
+    for name in &["gutter", "primary-cursor", "status-line", "error", "comment", "keyword"] {
+        assert!(faces.faces.contains_key(*name), "{} isn't in the default theme", name);
+    }
+}
@@ -0,0 +1,48 @@
+// Themes are files of faces, see resources/themes/default.theme. A few themes
+// are built in, and more can be put in the themes directory of the configuration.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::style::{Faces, DEFAULT_THEME};
+
+const BUILTIN_THEMES: &[(&str, &str)] = &[
+    ("default", DEFAULT_THEME),
+    ("light", include_str!("../resources/themes/light.theme")),
+];
+
+fn themes_dir() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("themes"))
+}
+
+/// The faces of a theme, given by name or by the path of a theme file. Files in
+/// the themes directory are used instead of builtin themes with the same name.
+pub fn load(name: &str) -> Result<Faces, String> {
+    let path = if name.contains('/') {
+        Some(PathBuf::from(name))
+    } else {
+        themes_dir().map(|dir| dir.join(format!("{}.theme", name)))
+    };
+
+    let theme = match path.filter(|path| path.exists()) {
+        Some(path) => fs::read_to_string(&path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?,
+        None => BUILTIN_THEMES
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, theme)| theme.to_string())
+            .ok_or_else(|| format!("No theme named {}", name))?,
+    };
+
+    // Themes only need to set the faces they change from the default theme
+    let mut faces = Faces::builtin();
+    faces.apply_theme(&theme).map_err(|e| format!("Error in theme {}, {}", name, e))?;
+    Ok(faces)
+}
+
+#[test]
+fn test_builtin_themes() {
+    let light = load("light").unwrap();
+    assert_ne!(light.get("default"), Faces::builtin().get("default"));
+    assert!(load("no-such-theme").is_err());
+}
@@ -0,0 +1,225 @@
+use std::ops::Range;
+
+// How many lines are kept visible above and below the cursor when scrolling to it
+const SCROLL_OFF_LINES: usize = 3;
+// How far from the left and right edges the cursor is kept, in pixels
+const SCROLL_OFF_PX: f32 = 48.;
+
+/// Which line numbers the gutter shows
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LineNumbers {
+    Off,
+    Absolute,
+    /// Distances to the line of the primary cursor, which itself gets its absolute number
+    Relative,
+}
+
+/// The part of the buffer which is shown in the window
+#[derive(Clone, Debug, PartialEq)]
+pub struct Viewport {
+    /// The first line shown
+    pub top_line: usize,
+    /// How far the text is scrolled to the right, in pixels
+    pub left: f32,
+    pub line_numbers: LineNumbers,
+    // Size of the window in pixels, set by the renderer
+    width: f32,
+    height: f32,
+    line_height: f32,
+    // Part of the width taken by the gutter, to the left of the text
+    gutter_width: f32,
+    // The part of a line scrolled by, which isn't shown until it adds up to a whole line
+    scroll_remainder: f32,
+}
+
+impl Viewport {
+    pub fn new() -> Self {
+        Self {
+            top_line: 0,
+            left: 0.,
+            line_numbers: LineNumbers::Absolute,
+            width: 0.,
+            height: 0.,
+            line_height: 1.,
+            gutter_width: 0.,
+            scroll_remainder: 0.,
+        }
+    }
+
+    pub fn set_size(&mut self, width: f32, height: f32, line_height: f32) {
+        self.width = width;
+        self.height = height;
+        self.line_height = line_height;
+    }
+
+    pub fn gutter_width(&self) -> f32 {
+        self.gutter_width
+    }
+
+    pub fn set_gutter_width(&mut self, gutter_width: f32) {
+        self.gutter_width = gutter_width;
+    }
+
+    // Width of the area the text is shown in
+    fn text_width(&self) -> f32 {
+        (self.width - self.gutter_width).max(0.)
+    }
+
+    /// How many digits the line numbers of a buffer with n_lines need, or 0 without a gutter
+    pub fn gutter_digits(&self, n_lines: usize) -> usize {
+        match self.line_numbers {
+            LineNumbers::Off => 0,
+            LineNumbers::Absolute | LineNumbers::Relative => n_lines.to_string().len(),
+        }
+    }
+
+    /// The number shown in the gutter for a line, given the line of the primary cursor
+    pub fn line_number(&self, line: usize, cursor_line: usize) -> Option<usize> {
+        match self.line_numbers {
+            LineNumbers::Off => None,
+            LineNumbers::Relative if line != cursor_line => Some((line as isize - cursor_line as isize).unsigned_abs()),
+            LineNumbers::Absolute | LineNumbers::Relative => Some(line + 1),
+        }
+    }
+
+    pub fn line_height(&self) -> f32 {
+        self.line_height
+    }
+
+    /// How many lines fit in the viewport, counting partly visible lines
+    pub fn height_lines(&self) -> usize {
+        (self.height / self.line_height).ceil() as usize
+    }
+
+    /// How many lines are completely visible
+    pub fn full_lines(&self) -> usize {
+        (self.height / self.line_height).floor() as usize
+    }
+
+    /// The lines which are at least partly visible, out of n_lines in the buffer
+    pub fn visible_lines(&self, n_lines: usize) -> Range<usize> {
+        self.top_line.min(n_lines)..(self.top_line + self.height_lines()).min(n_lines)
+    }
+
+    /// Scrolls down by the number of lines, or up if negative. The last line
+    /// can be scrolled up to the top of the viewport, but not further.
+    pub fn scroll_lines(&mut self, delta: isize, n_lines: usize) {
+        let max_top = n_lines.saturating_sub(1) as isize;
+        self.top_line = (self.top_line as isize + delta).max(0).min(max_top) as usize;
+    }
+
+    /// Scrolls by a number of lines which needn't be whole, e.g. from a trackpad.
+    /// Parts of lines are kept until they add up to whole lines.
+    pub fn scroll_partial_lines(&mut self, delta: f32, n_lines: usize) {
+        let delta = self.scroll_remainder + delta;
+        self.scroll_remainder = delta.fract();
+        self.scroll_lines(delta.trunc() as isize, n_lines);
+    }
+
+    pub fn scroll_horizontally(&mut self, delta: f32) {
+        self.left = (self.left + delta).max(0.);
+    }
+
+    /// Where a line is shown, in pixels from the top of the viewport
+    pub fn y_of_line(&self, line: usize) -> f32 {
+        (line as f32 - self.top_line as f32) * self.line_height
+    }
+
+    /// Scrolls so that a line is shown as close to y as possible, e.g. to keep
+    /// it in place when the line height changes
+    pub fn scroll_line_to_y(&mut self, line: usize, y: f32) {
+        let lines_above = (y / self.line_height).round().max(0.) as usize;
+        self.top_line = line.saturating_sub(lines_above);
+    }
+
+    /// Scrolls as little as possible to show a position, given as a line and
+    /// its x position in pixels
+    pub fn scroll_to_show(&mut self, line: usize, x: f32) {
+        let full_lines = self.full_lines();
+        if full_lines > 0 {
+            // Small viewports can't fit the margins
+            let margin = SCROLL_OFF_LINES.min((full_lines - 1) / 2);
+            if line < self.top_line + margin {
+                self.top_line = line.saturating_sub(margin);
+            } else if line + margin >= self.top_line + full_lines {
+                self.top_line = line + margin + 1 - full_lines;
+            }
+        }
+
+        let width = self.text_width();
+        if width > 0. {
+            let margin = SCROLL_OFF_PX.min(width / 2.);
+            if x < self.left + margin {
+                self.left = (x - margin).max(0.);
+            } else if x > self.left + width - margin {
+                self.left = x + margin - width;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_scroll_to_show() {
+    let mut viewport = Viewport::new();
+    viewport.set_size(200., 100., 10.);
+    assert_eq!(viewport.visible_lines(5), 0..5);
+
+    viewport.scroll_to_show(9, 0.);
+    assert_eq!(viewport.top_line, 3);
+    viewport.scroll_to_show(6, 0.);
+    assert_eq!(viewport.top_line, 3);
+    viewport.scroll_to_show(4, 0.);
+    assert_eq!(viewport.top_line, 1);
+    assert_eq!(viewport.visible_lines(100), 1..11);
+
+    viewport.scroll_to_show(0, 300.);
+    assert_eq!(viewport.top_line, 0);
+    assert_eq!(viewport.left, 148.);
+    viewport.scroll_to_show(0, 160.);
+    assert_eq!(viewport.left, 112.);
+
+    viewport.scroll_lines(-10, 100);
+    assert_eq!(viewport.top_line, 0);
+    viewport.scroll_lines(1000, 100);
+    assert_eq!(viewport.top_line, 99);
+
+    viewport.top_line = 10;
+    for _ in 0..6 {
+        viewport.scroll_partial_lines(0.25, 100);
+    }
+    assert_eq!(viewport.top_line, 11);
+    viewport.scroll_partial_lines(-1.5, 100);
+    assert_eq!(viewport.top_line, 10);
+}
+
+#[test]
+fn test_keep_line_in_place() {
+    let mut viewport = Viewport::new();
+    viewport.set_size(200., 100., 10.);
+    viewport.top_line = 20;
+
+    let y = viewport.y_of_line(25);
+    assert_eq!(y, 50.);
+    viewport.set_size(200., 100., 20.);
+    viewport.scroll_line_to_y(25, y);
+    assert_eq!(viewport.top_line, 22);
+
+    viewport.scroll_line_to_y(1, y);
+    assert_eq!(viewport.top_line, 0);
+}
+
+#[test]
+fn test_line_numbers() {
+    let mut viewport = Viewport::new();
+    assert_eq!(viewport.gutter_digits(100), 3);
+    assert_eq!(viewport.line_number(4, 2), Some(5));
+
+    viewport.line_numbers = LineNumbers::Relative;
+    assert_eq!(viewport.line_number(4, 2), Some(2));
+    assert_eq!(viewport.line_number(0, 2), Some(2));
+    assert_eq!(viewport.line_number(2, 2), Some(3));
+
+    viewport.line_numbers = LineNumbers::Off;
+    assert_eq!(viewport.gutter_digits(100), 0);
+    assert_eq!(viewport.line_number(4, 2), None);
+}